@@ -262,6 +262,28 @@ export class RenderMap {
     this.map.envelopes.splice(id, 1)
   }
 
+  // removes an envelope in use, the layers and quads using it are left without envelope.
+  forceRemoveEnvelope(id: number) {
+    const env = this.map.envelopes[id]
+
+    for (const rgroup of this.groups) {
+      for (const rlayer of rgroup.layers) {
+        if (rlayer instanceof RenderTilesLayer && rlayer.layer.colorEnv === env) {
+          rlayer.layer.colorEnv = null
+        }
+        else if (rlayer instanceof RenderQuadsLayer) {
+          for (const quad of rlayer.layer.quads) {
+            if (quad.posEnv === env) quad.posEnv = null
+            if (quad.colorEnv === env) quad.colorEnv = null
+          }
+          rlayer.recompute()
+        }
+      }
+    }
+
+    this.removeEnvelope(id)
+  }
+
   editTile(e: EditTile) {
     const rgroup = this.groups[e.g]
     const rlayer = rgroup.layers[e.l] as RenderAnyTilesLayer<PhysicsLayer | TilesLayer>
@@ -27,6 +27,14 @@ export class Texture {
     }
   }
 
+  // recreates the texture after the data of the image changed.
+  reload() {
+    if (this.tex !== null) gl.deleteTexture(this.tex)
+    this.tex = null
+    this.loaded = false
+    this.load()
+  }
+
   private initTexture(img: ImageSource) {
     this.tex = gl.createTexture()
     const interp = this.interpolate ? gl.LINEAR : gl.NEAREST
@@ -234,6 +234,10 @@ export function reverse(map: Map, pkt: SendPacket<ReqKey>): [ReqKey, Req[ReqKey]
     const [g, l] = pkt.content as Send['edit/automap']
     return ['edit/tiles', rev_automap(map, g, l)]
   }
+  else if (pkt.type === 'edit/automap_region') {
+    const [g, l] = pkt.content as Send['edit/automap_region']
+    return ['edit/tiles', rev_automap(map, g, l)]
+  }
   else if (pkt.type === 'move/envelope') {
     const [src, tgt] = pkt.content as Send['move/envelope']
     return ['move/envelope', [tgt, src]]
@@ -292,6 +296,7 @@ const recvKeys: RecvKey[] = [
   "edit/tiles",
   "edit/quad",
   "edit/automap",
+  "edit/automap_region",
   "move/envelope",
   "move/group",
   "move/layer",
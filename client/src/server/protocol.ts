@@ -19,6 +19,7 @@ export interface Config {
 export interface MapDetail {
   name: string
   users: number
+  thumbnail: string
 }
 
 export interface Tiles {
@@ -67,9 +68,147 @@ export interface Span {
   col_end: number
 }
 
+export type Severity = 'error' | 'warning'
+
 export interface AutomapperDiagnostic {
   span: Span
   msg: string
+  severity: Severity
+}
+
+export type Change = 'added' | 'removed' | 'modified'
+
+export interface IndexDiff {
+  change: Change
+  base?: number
+  other?: number
+}
+
+export interface ItemDiff extends IndexDiff {
+  name: string
+}
+
+export interface LayerDiff extends ItemDiff {
+  kind: string
+  properties: boolean
+  resized?: [{ w: number, h: number }, { w: number, h: number }]
+  tiles: { x: number, y: number, w: number, h: number }[]
+  quads: IndexDiff[]
+}
+
+export interface GroupDiff extends ItemDiff {
+  properties: boolean
+  layers: LayerDiff[]
+}
+
+export interface MapDiff {
+  info: boolean
+  images: ItemDiff[]
+  envelopes: ItemDiff[]
+  groups: GroupDiff[]
+}
+
+export type ConflictItem =
+  | { item: 'info', field: string }
+  | { item: 'image', image: number }
+  | { item: 'envelope', envelope: number }
+  | { item: 'sound', sound: number }
+  | { item: 'group', group: number }
+  | { item: 'layer', group: number, layer: number }
+  | { item: 'quad', group: number, layer: number, quad: number }
+
+export type ConflictKind =
+  | { conflict: 'edited' }
+  | { conflict: 'deleted_edited', deleted_by: 'ours' | 'theirs' }
+  | { conflict: 'tiles', rects: { x: number, y: number, w: number, h: number }[] }
+
+export type Conflict = ConflictItem & ConflictKind
+
+export interface MergedMap {
+  map: Base64
+  conflicts: Conflict[]
+}
+
+export interface OptimizeReport {
+  layers: [string, string][]
+  groups: string[]
+  duplicate_images: string[]
+  images: string[]
+  envelopes: string[]
+  size_before: number
+  size_after: number
+}
+
+export interface ItemStats {
+  name: string
+  size: number
+}
+
+export interface LayerStats extends ItemStats {
+  kind: string
+  tiles?: Record<number, number>
+  quads?: number
+}
+
+export interface GroupStats extends ItemStats {
+  layers: LayerStats[]
+}
+
+export interface MapStats {
+  images: ItemStats[]
+  envelopes: ItemStats[]
+  sounds: ItemStats[]
+  groups: GroupStats[]
+  totals: {
+    images: number
+    envelopes: number
+    sounds: number
+    layers: number
+    file: number
+    max: number
+  }
+}
+
+export interface EnvelopeEval {
+  times?: number[]
+  range?: { start: number, end: number, step: number }
+  offset?: number
+  local_time?: number
+}
+
+export type EnvValue = { x: number, y: number, rotation: number }
+  | { r: number, g: number, b: number, a: number }
+  | number
+
+export interface EnvSample {
+  time: number
+  value: EnvValue
+}
+
+export interface EnvelopeUsage {
+  tiles_color: [number, number][]
+  quads_position: [number, number, number][]
+  quads_color: [number, number, number][]
+  sources_position: [number, number, number][]
+  sources_sound: [number, number, number][]
+}
+
+export interface LibraryAutomapper {
+  name: string
+  image: string
+  kind: AutomapperKind
+  versions: number[]
+}
+
+export interface ImportedAutomapper {
+  version: number
+  diagnostics: AutomapperDiagnostic[]
+}
+
+export interface UploadStatus {
+  id: string
+  size: number
+  received: number
 }
 
 export type MapCreation = {
@@ -84,6 +223,8 @@ export type MapCreation = {
   } 
 } | {
   upload: Base64
+} | {
+  upload_id: string
 })
 
 export interface MapGetReq {
@@ -102,6 +243,12 @@ export interface MapGetReq {
   quad: [number, number, number]
   automappers: undefined
   automapper: string
+  automap_preview: [number, number, AutomapPreview]
+  diff: Base64 | null
+  stats: undefined
+  envelope_eval: [number, EnvelopeEval]
+  envelope_usage: undefined
+  find_tiles: TilesQuery
 }
 
 export interface MapGetResp {
@@ -120,10 +267,16 @@ export interface MapGetResp {
   quad: MapDir.Quad
   automappers: AutomapperDetail[]
   automapper: string
+  automap_preview: AutomapDiff
+  diff: MapDiff
+  stats: MapStats
+  envelope_eval: EnvSample[]
+  envelope_usage: EnvelopeUsage[]
+  find_tiles: TilesFound
 }
 
 export interface MapCreateReq {
-  image: [string, Base64 | MapDir.ExternalImage]
+  image: [string, Base64 | { upload_id: string } | (MapDir.ExternalImage & { embed?: boolean })]
   envelope: Require<MapDir.Envelope, "type">
   group: Partial<MapDir.Group>
   layer: [number, Require<MapDir.Layer, "type">]
@@ -139,7 +292,16 @@ export interface MapEditReq {
   layer: [number, number, Require<MapDir.Layer, "type">]
   tiles: [number, number, Tiles]
   quad: [number, number, number, MapDir.Quad]
+  quads: [number, number, number[], QuadsOp]
   automap: [number, number]
+  automap_region: [number, number, AutomapRegion]
+  image: [number, Partial<{ name: string, data: Base64 | { upload_id: string } | MapDir.ExternalImage }>]
+  embed_image: number
+  unembed_image: number
+  optimize: undefined
+  transform: MapTransform
+  replace_tiles: [TilesQuery, TilesReplacement]
+  remap_tiles: [number, number, RemapTiles]
 }
 
 export interface MapReorderReq {
@@ -153,12 +315,130 @@ export interface MapReorderReq {
 export interface MapDelReq {
   image: number
   envelope: number
+  envelope_force: number
   group: number
   layer: [number, number]
   quad: [number, number, number]
   automapper: string
 }
 
+export type QuadsOp = {
+  type: 'translate'
+  offset: MapDir.Point<string>
+} | {
+  type: 'rotate'
+  angle: number
+  center?: MapDir.Point<string>
+} | {
+  type: 'scale'
+  factor: MapDir.Point<number>
+  center?: MapDir.Point<string>
+} | {
+  type: 'color'
+  color: MapDir.Color<number>
+} | {
+  type: 'envelopes'
+  position_env?: number | null
+  position_env_offset?: number
+  color_env?: number | null
+  color_env_offset?: number
+} | {
+  type: 'snap' | 'delete'
+}
+
+export type Transform = 'flip_x' | 'flip_y' | 'rotate_cw' | 'rotate_ccw' | { shift: { x: number, y: number } }
+
+export interface MapTransform {
+  transform: Transform
+  region?: { group: number, x: number, y: number, w: number, h: number }
+}
+
+export interface TilesQuery {
+  id: number
+  flags?: number
+  number?: number
+  layers?: [number, number][]
+  rect?: { x: number, y: number, w: number, h: number }
+}
+
+export interface TilesFound {
+  layers: {
+    group: number
+    layer: number
+    count: number
+    positions: [number, number][]
+  }[]
+  truncated: boolean
+}
+
+export interface TilesReplacement {
+  id: number
+  flags?: number
+  number?: number
+}
+
+export interface RemapEntry {
+  from: number
+  from_flags?: number
+  to: number
+  to_flags?: number
+}
+
+export type Remapping = {
+  type: 'table'
+  entries: RemapEntry[]
+} | {
+  type: 'automappers'
+  from: string
+  to: string
+}
+
+export interface RemapTiles {
+  remapping: Remapping
+  image?: number
+}
+
+export interface RemapReport {
+  remapped: number
+  unmapped: number[]
+}
+
+export interface AutomapRegion {
+  rect?: { x: number, y: number, w: number, h: number }
+  config?: string
+  seed?: number
+}
+
+export interface AutomapPreview extends AutomapRegion {
+  name: string
+  file: string
+}
+
+export interface AutomapDiff {
+  seed: number
+  changes: { x: number, y: number, id: number, flags: number }[]
+}
+
+export interface MapDuplicateReq {
+  group: number
+  layer: [number, number]
+  quads: [number, number, number[]]
+}
+
+export interface MapCopyReq {
+  group: [string, number]
+  layer: [string, [number, number], number]
+  image: [string, number]
+  envelope: [string, number]
+}
+
+export interface CopyReport {
+  index: number[]
+  images: number[]
+  envelopes: number[]
+  sounds: number[]
+}
+
 export interface MapReq {
   cursor: Cursor
   save: undefined
@@ -167,6 +447,8 @@ export interface MapReq {
   edit: MapEditReq
   move: MapReorderReq
   delete: MapDelReq
+  duplicate: MapDuplicateReq
+  copy: MapCopyReq
 }
 
 export interface GetReq {
@@ -199,6 +481,7 @@ export interface Broadcast {
   map_deleted: string
   users: number
   saved: undefined
+  reload: undefined
 }
 
 export type Result<T> = {
@@ -223,6 +506,12 @@ export interface Send {
   "get/quad": MapGetReq['quad']
   "get/automappers": MapGetReq['automappers']
   "get/automapper": MapGetReq['automapper']
+  "get/automap_preview": MapGetReq['automap_preview']
+  "get/diff": MapGetReq['diff']
+  "get/stats": MapGetReq['stats']
+  "get/envelope_eval": MapGetReq['envelope_eval']
+  "get/envelope_usage": MapGetReq['envelope_usage']
+  "find/tiles": MapGetReq['find_tiles']
   "create/image": MapCreateReq['image']
   "create/envelope": MapCreateReq['envelope']
   "create/group": MapCreateReq['group']
@@ -236,19 +525,43 @@ export interface Send {
   "edit/layer": MapEditReq['layer']
   "edit/tiles": MapEditReq['tiles']
   "edit/quad": MapEditReq['quad']
+  "edit/quads": MapEditReq['quads']
   "edit/automap": MapEditReq['automap']
+  "edit/automap_region": MapEditReq['automap_region']
+  "edit/image": MapEditReq['image']
+  "edit/embed_image": MapEditReq['embed_image']
+  "edit/unembed_image": MapEditReq['unembed_image']
+  "edit/optimize": MapEditReq['optimize']
+  "edit/transform": MapEditReq['transform']
+  "replace/tiles": MapEditReq['replace_tiles']
+  "edit/remap_tiles": MapEditReq['remap_tiles']
   "move/envelope": MapReorderReq['envelope']
   "move/group": MapReorderReq['group']
   "move/layer": MapReorderReq['layer']
   "move/quad": MapReorderReq['quad']
   "delete/image": MapDelReq['image']
   "delete/envelope": MapDelReq['envelope']
+  "delete/envelope_force": MapDelReq['envelope_force']
   "delete/group": MapDelReq['group']
   "delete/layer": MapDelReq['layer']
   "delete/quad": MapDelReq['quad']
   "delete/automapper": MapDelReq['automapper']
+  "duplicate/group": MapDuplicateReq['group']
+  "duplicate/layer": MapDuplicateReq['layer']
+  "duplicate/quads": MapDuplicateReq['quads']
+  "copy/group": MapCopyReq['group']
+  "copy/layer": MapCopyReq['layer']
+  "copy/image": MapCopyReq['image']
+  "copy/envelope": MapCopyReq['envelope']
   "cursor": Cursor
   "save": undefined
+  "upload/start": number
+  "upload/status": string
+  "upload/chunk": [string, number, Base64]
+  "upload/cancel": string
+  "library/list": undefined
+  "library/import": [string, number | null]
+  "library/publish": string
   "join": string
   "leave": string
   "create": EditReq['map']
@@ -271,6 +584,12 @@ export interface Resp {
   "get/quad": MapGetResp['quad']
   "get/automappers": MapGetResp['automappers']
   "get/automapper": MapGetResp['automapper']
+  "get/automap_preview": MapGetResp['automap_preview']
+  "get/diff": MapGetResp['diff']
+  "get/stats": MapGetResp['stats']
+  "get/envelope_eval": MapGetResp['envelope_eval']
+  "get/envelope_usage": MapGetResp['envelope_usage']
+  "find/tiles": MapGetResp['find_tiles']
   "create/image": undefined
   "create/envelope": undefined
   "create/group": undefined
@@ -284,19 +603,43 @@ export interface Resp {
   "edit/layer": undefined
   "edit/tiles": undefined
   "edit/quad": undefined
+  "edit/quads": undefined
   "edit/automap": undefined
+  "edit/automap_region": AutomapDiff
+  "edit/image": undefined
+  "edit/embed_image": undefined
+  "edit/unembed_image": undefined
+  "edit/optimize": OptimizeReport
+  "edit/transform": undefined
+  "replace/tiles": number
+  "edit/remap_tiles": RemapReport
   "move/envelope": undefined
   "move/group": undefined
   "move/layer": undefined
   "move/quad": undefined
   "delete/image": undefined
   "delete/envelope": undefined
+  "delete/envelope_force": undefined
   "delete/group": undefined
   "delete/layer": undefined
   "delete/quad": undefined
   "delete/automapper": undefined
+  "duplicate/group": [number]
+  "duplicate/layer": [number, number]
+  "duplicate/quads": number[]
+  "copy/group": CopyReport
+  "copy/layer": CopyReport
+  "copy/image": CopyReport
+  "copy/envelope": CopyReport
   "cursor": undefined
   "save": undefined
+  "upload/start": UploadStatus
+  "upload/status": UploadStatus
+  "upload/chunk": UploadStatus
+  "upload/cancel": undefined
+  "library/list": LibraryAutomapper[]
+  "library/import": ImportedAutomapper
+  "library/publish": number
   "join": undefined
   "leave": undefined
   "create": undefined
@@ -317,20 +660,38 @@ export interface Recv {
   "edit/layer": MapEditReq['layer']
   "edit/tiles": MapEditReq['tiles']
   "edit/quad": MapEditReq['quad']
+  "edit/quads": MapEditReq['quads']
   "edit/automap": MapEditReq['automap']
+  "edit/automap_region": MapEditReq['automap_region']
+  "edit/image": MapEditReq['image']
+  "edit/embed_image": MapEditReq['embed_image']
+  "edit/unembed_image": MapEditReq['unembed_image']
+  "edit/optimize": MapEditReq['optimize']
+  "edit/transform": MapEditReq['transform']
+  "replace/tiles": MapEditReq['replace_tiles']
+  "edit/remap_tiles": MapEditReq['remap_tiles']
   "move/envelope": MapReorderReq['envelope']
   "move/group": MapReorderReq['group']
   "move/layer": MapReorderReq['layer']
   "delete/image": MapDelReq['image']
   "delete/envelope": MapDelReq['envelope']
+  "delete/envelope_force": MapDelReq['envelope_force']
   "delete/group": MapDelReq['group']
   "delete/layer": MapDelReq['layer']
   "delete/quad": MapDelReq['quad']
   "delete/automapper": MapDelReq['automapper']
+  "duplicate/group": MapDuplicateReq['group']
+  "duplicate/layer": MapDuplicateReq['layer']
+  "duplicate/quads": MapDuplicateReq['quads']
+  "copy/group": MapCopyReq['group']
+  "copy/layer": MapCopyReq['layer']
+  "copy/image": MapCopyReq['image']
+  "copy/envelope": MapCopyReq['envelope']
   "map_created": string
   "map_deleted": string
   "users": number
   "saved": undefined
+  "reload": undefined
 }
 
 export type Req = Send | Recv
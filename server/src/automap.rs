@@ -0,0 +1,219 @@
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use twmap::{
+    automapper::{Automapper, Chance},
+    AutomapperConfig, Tile,
+};
+use vek::{Rect, Vec2};
+
+use crate::{error::Error, tw_automapper::TwAutomapper};
+
+// Automapping of a tiles layer with the automapper of its image, either a DDNet
+// .rules or a Teeworlds .json automapper.
+
+pub enum LayerAutomapper {
+    DDNet(Automapper),
+    Teeworlds(TwAutomapper),
+}
+
+/// Overrides of the automapper settings of the layer. The layer itself is not changed.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutomapRegion {
+    /// Region to automap, in tiles, the whole layer if not given. The tiles around it
+    /// are used by the rules but are not changed.
+    pub rect: Option<Rect<u32, u32>>,
+    /// Name of the config to use instead of the config of the layer.
+    pub config: Option<String>,
+    /// Seed to use instead of the seed of the layer, 0 for a random seed.
+    pub seed: Option<u32>,
+}
+
+/// An automapper source to try on a layer without saving it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AutomapPreview {
+    /// File name of the automapper, its extension gives the kind of automapper.
+    pub name: String,
+    pub file: String,
+    #[serde(flatten)]
+    pub region: AutomapRegion,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TileChange {
+    pub x: u32,
+    pub y: u32,
+    pub id: u8,
+    pub flags: u8,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AutomapDiff {
+    /// The seed used, to automap again with the same result.
+    pub seed: u32,
+    /// The tiles that changed, in row order.
+    pub changes: Vec<TileChange>,
+}
+
+impl LayerAutomapper {
+    fn config_index(&self, name: &str) -> Option<usize> {
+        match self {
+            LayerAutomapper::DDNet(am) => am.configs.iter().position(|c| c.name == name),
+            LayerAutomapper::Teeworlds(am) => am.configs.iter().position(|c| c.name == name),
+        }
+    }
+
+    /// How far the rules of the config look around a tile, added up for the runs that
+    /// see the result of the previous ones. Changing a tile can only change the result
+    /// of the automapper within this distance. `None` if a run rewrites the tiles in
+    /// place (Teeworlds configs and NoLayerCopy runs): the rules then see the tiles
+    /// they already changed, and a change can spread through the whole layer.
+    pub fn reach(&self, config: &AutomapperConfig) -> Option<u32> {
+        fn max_offset<'a>(offsets: impl Iterator<Item = &'a Vec2<i32>>) -> u32 {
+            offsets
+                .map(|o| o.x.unsigned_abs().max(o.y.unsigned_abs()))
+                .max()
+                .unwrap_or(0)
+        }
+
+        let Some(index) = config.config else {
+            return Some(0);
+        };
+        match self {
+            LayerAutomapper::DDNet(am) => am.configs.get(index as usize).map_or(Some(0), |c| {
+                c.runs
+                    .iter()
+                    .map(|run| {
+                        run.layer_copy.then(|| {
+                            max_offset(
+                                run.rules
+                                    .iter()
+                                    .flat_map(|r| &r.conditions)
+                                    .map(|c| &c.offset),
+                            )
+                        })
+                    })
+                    .sum()
+            }),
+            LayerAutomapper::Teeworlds(am) => match am.configs.get(index as usize) {
+                Some(_) => None,
+                None => Some(0),
+            },
+        }
+    }
+
+    // whether the result of the config depends on the position of the tiles.
+    fn is_random(&self, config: &AutomapperConfig) -> bool {
+        let Some(index) = config.config else {
+            return false;
+        };
+        match self {
+            LayerAutomapper::DDNet(am) => am.configs.get(index as usize).is_some_and(|c| {
+                c.runs
+                    .iter()
+                    .flat_map(|run| &run.rules)
+                    .any(|rule| rule.chance != Chance::Always)
+            }),
+            LayerAutomapper::Teeworlds(am) => am
+                .configs
+                .get(index as usize)
+                .is_some_and(|c| c.rules.iter().any(|rule| rule.probability.is_some())),
+        }
+    }
+
+    // the part of the layer to automap so that the tiles of the rect get the same result
+    // as in a complete run: the rect and the tiles the rules see around it.
+    fn window(
+        &self,
+        config: &AutomapperConfig,
+        (x, y, x_end, y_end): (usize, usize, usize, usize),
+        (w, h): (usize, usize),
+    ) -> (usize, usize, usize, usize) {
+        let Some(reach) = self.reach(config) else {
+            return (0, 0, w, h);
+        };
+        let reach = reach as usize;
+        let x_end = x_end.saturating_add(reach).min(w);
+        let y_end = y_end.saturating_add(reach).min(h);
+        // the random rules depend on the position of the tiles, which must not move.
+        if self.is_random(config) {
+            (0, 0, x_end, y_end)
+        } else {
+            (
+                x.saturating_sub(reach),
+                y.saturating_sub(reach),
+                x_end,
+                y_end,
+            )
+        }
+    }
+
+    pub fn run(&self, config: &AutomapperConfig, tiles: &mut Array2<Tile>) -> Result<(), Error> {
+        match self {
+            LayerAutomapper::DDNet(am) => am.run(config, tiles),
+            LayerAutomapper::Teeworlds(am) => am.run(config, tiles),
+        }
+        .map_err(|_| Error::Automapper("config out of bounds".to_owned()))
+    }
+
+    /// Automaps the region of the layer and returns the tiles that changed.
+    pub fn run_region(
+        &self,
+        layer_config: &AutomapperConfig,
+        region: &AutomapRegion,
+        tiles: &mut Array2<Tile>,
+    ) -> Result<AutomapDiff, Error> {
+        let mut config = layer_config.clone();
+        if let Some(name) = &region.config {
+            let index = self
+                .config_index(name)
+                .ok_or_else(|| Error::Automapper(format!("no config named '{name}'")))?;
+            config.config = Some(index as u16);
+        }
+        if let Some(seed) = region.seed {
+            config.seed = seed;
+        }
+        // the random seed is chosen here so that it can be returned.
+        while config.seed == 0 {
+            config.seed = rand::random();
+        }
+
+        let (h, w) = tiles.dim();
+        let (x, y, x_end, y_end) = match region.rect {
+            Some(rect) => {
+                let x_end = (rect.x as usize).saturating_add(rect.w as usize);
+                let y_end = (rect.y as usize).saturating_add(rect.h as usize);
+                if x_end > w || y_end > h {
+                    return Err(Error::TilesOutOfBounds);
+                }
+                (rect.x as usize, rect.y as usize, x_end, y_end)
+            }
+            None => (0, 0, w, h),
+        };
+
+        let (wx, wy, wx_end, wy_end) = self.window(&config, (x, y, x_end, y_end), (w, h));
+        let mut automapped = tiles.slice(s![wy..wy_end, wx..wx_end]).to_owned();
+        self.run(&config, &mut automapped)?;
+
+        let mut changes = Vec::new();
+        for ((ty, tx), tile) in tiles.slice_mut(s![y..y_end, x..x_end]).indexed_iter_mut() {
+            let new = automapped[(y + ty - wy, x + tx - wx)];
+            if *tile != new {
+                *tile = new;
+                changes.push(TileChange {
+                    x: (x + tx) as u32,
+                    y: (y + ty) as u32,
+                    id: new.id,
+                    flags: new.flags.bits(),
+                });
+            }
+        }
+
+        Ok(AutomapDiff {
+            seed: config.seed,
+            changes,
+        })
+    }
+}
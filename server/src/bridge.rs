@@ -204,16 +204,16 @@ impl Server {
 
         let server_2 = server.clone();
 
-        let fut_recv = ws_recv
-            .try_chunks(2)
-            .map_err(|_| Error::BridgeClosed)
-            .try_for_each(move |v| {
-                let (addr_msg, payload_msg) = match v.into_iter().collect_tuple() {
+        // requests are handled asynchronously, one after the other.
+        let fut_recv = Box::pin(async move {
+            let mut chunks = ws_recv.try_chunks(2).map_err(|_| Error::BridgeClosed);
+            while let Some(v) = chunks.next().await {
+                let (addr_msg, payload_msg) = match v?.into_iter().collect_tuple() {
                     Some((TungsteniteMessage::Text(m1), TungsteniteMessage::Text(m2))) => (m1, m2),
-                    _ => return futures::future::err(Error::BridgeClosed),
+                    _ => return Err(Error::BridgeClosed),
                 };
 
-                let res = || -> Option<()> {
+                let res = async {
                     let addr: SocketAddr = serde_json::from_str(&addr_msg).ok()?;
                     let peer = match bridge_peers.get_mut(&addr) {
                         Some(peer) => peer,
@@ -258,7 +258,7 @@ impl Server {
                         Ok(pkt) => match &pkt.content {
                             Request::JoinMap(map) => {
                                 if map == &cfg.map {
-                                    server.handle_request(peer, pkt);
+                                    server.handle_request(peer, pkt).await;
                                 } else {
                                     Server::send(
                                         &peer,
@@ -272,7 +272,7 @@ impl Server {
                                 maps.retain(|m| m.name == cfg.map);
                                 server.do_respond(peer, &pkt, Ok(Response::Maps(maps)));
                             }
-                            _ => server.handle_request(peer, pkt),
+                            _ => server.handle_request(peer, pkt).await,
                         },
                         Err(e) => {
                             log::error!("failed to parse message: {e} in {payload_msg}");
@@ -285,10 +285,13 @@ impl Server {
                     };
 
                     Some(())
-                }();
+                }
+                .await;
 
-                futures::future::ready(res.ok_or(Error::BridgeFailure))
-            });
+                res.ok_or(Error::BridgeFailure)?;
+            }
+            Ok(())
+        });
 
         let url = cfg.url.clone();
         log::info!("bridge connected to {}", url);
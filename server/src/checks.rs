@@ -1,4 +1,6 @@
-use crate::{error::Error, protocol::*, twmap_map_checks::InternalMapChecking};
+use crate::{
+    error::Error, protocol::*, quads_edit::QuadsOp, twmap_map_checks::InternalMapChecking,
+};
 
 pub(crate) trait PartialCheck {
     fn check_self(&self) -> Result<(), Error> {
@@ -204,6 +206,51 @@ impl PartialCheck for PartialQuadsLayer {
 
 impl PartialCheck for PartialPhysicsLayer {}
 
+impl PartialCheck for QuadsOp {
+    fn check_self(&self) -> Result<(), Error> {
+        match self {
+            QuadsOp::Rotate { angle, .. } if !angle.is_finite() => {
+                Err(Error::Invalid("rotation angle"))
+            }
+            QuadsOp::Scale { factor, .. } if !factor.x.is_finite() || !factor.y.is_finite() => {
+                Err(Error::Invalid("scale factor"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_map(&self, map: &twmap::TwMap) -> Result<(), Error> {
+        if let QuadsOp::Envelopes {
+            position_env,
+            color_env,
+            ..
+        } = self
+        {
+            if let Some(Some(index)) = position_env {
+                let env = map
+                    .envelopes
+                    .get(*index as usize)
+                    .ok_or(Error::EnvelopeNotFound)?;
+                if !matches!(env, twmap::Envelope::Position(_)) {
+                    return Err(Error::WrongEnvelopeType);
+                }
+            }
+
+            if let Some(Some(index)) = color_env {
+                let env = map
+                    .envelopes
+                    .get(*index as usize)
+                    .ok_or(Error::EnvelopeNotFound)?;
+                if !matches!(env, twmap::Envelope::Color(_)) {
+                    return Err(Error::WrongEnvelopeType);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl PartialCheck for twmap::Quad {
     fn check_self(&self) -> Result<(), Error> {
         Ok(())
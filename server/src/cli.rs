@@ -1,13 +1,18 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[clap(name = "TWWE Server")]
 #[clap(author = "Mathis Brossier <mathis.brossier@gmail.com>")]
 #[clap(version = "0.1")]
 #[clap(about = "TeeWorlds Web Editor server", long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct Cli {
+    /// Run a one-off command on map files instead of starting the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Address and port to listen to (addr:port)
     #[arg(default_value = "127.0.0.1:16800")]
     pub addr: String,
@@ -39,6 +44,23 @@ pub struct Cli {
     #[arg(name = "rpp", long)]
     pub rpp_path: Option<PathBuf>,
 
+    /// Directory of the automapper library shared by all maps.
+    #[arg(name = "library", long)]
+    pub library_dir: Option<PathBuf>,
+
+    /// Maximum duration of a rules++ compilation, in seconds. Default: 10s.
+    #[arg(long, default_value_t = 10)]
+    pub rpp_timeout: u64,
+
+    /// Maximum size of the output of a rules++ compilation (errors and generated
+    /// rules), in KiB. Default: 4MiB.
+    #[arg(long, default_value_t = 4 * 1024)]
+    pub rpp_max_output: u64,
+
+    /// Maximum memory used by a rules++ compilation, in MiB. Default: 100MiB.
+    #[arg(long, default_value_t = 100)]
+    pub rpp_max_memory: u64,
+
     /// Maximum number of maps in both --maps and --data folders. Default: 1000.
     #[arg(long, default_value_t = 1000)]
     pub max_maps: usize,
@@ -52,3 +74,34 @@ pub struct Cli {
     #[arg(long, default_value_t = 100)]
     pub max_connections: usize,
 }
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print the differences between two map files as JSON
+    Diff {
+        /// Path to the original map
+        base: PathBuf,
+        /// Path to the modified map
+        other: PathBuf,
+    },
+    /// Merge the changes of two copies of a map, print the conflicts as JSON
+    Merge {
+        /// Path to the common ancestor map
+        base: PathBuf,
+        /// Path to our copy of the map
+        ours: PathBuf,
+        /// Path to their copy of the map
+        theirs: PathBuf,
+        /// Path to write the merged map to. Default: overwrite ours, like a git merge driver.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Remove unused images, envelopes, empty layers and groups, print what was removed as JSON
+    Optimize {
+        /// Path to the map
+        map: PathBuf,
+        /// Path to write the optimized map to. Default: overwrite the map.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
@@ -0,0 +1,77 @@
+use std::{path::Path, process::ExitCode};
+
+use crate::{
+    cli::Command, error::Error, map_diff::diff_maps, map_merge::merge_maps,
+    map_optimize::optimize_map, room::load_map,
+};
+
+fn read_map(path: &Path) -> Result<twmap::TwMap, Error> {
+    load_map(path).map_err(|e| Error::Map(format!("{}: {e}", path.display())))
+}
+
+fn diff(base: &Path, other: &Path) -> Result<ExitCode, Error> {
+    let diff = diff_maps(&read_map(base)?, &read_map(other)?);
+    let json =
+        serde_json::to_string_pretty(&diff).map_err(|e| Error::Internal(e.to_string().into()))?;
+    println!("{json}");
+
+    // same convention as diff(1)
+    Ok(if diff.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    })
+}
+
+fn merge(base: &Path, ours: &Path, theirs: &Path, output: &Path) -> Result<ExitCode, Error> {
+    let (mut map, conflicts) = merge_maps(&read_map(base)?, &read_map(ours)?, &read_map(theirs)?);
+    // saved in memory first to not truncate ours if the merged map is invalid
+    let mut buf = Vec::new();
+    map.save(&mut buf).map_err(|e| Error::Map(e.to_string()))?;
+    std::fs::write(output, buf).map_err(|e| Error::Internal(e.to_string().into()))?;
+
+    let json = serde_json::to_string_pretty(&conflicts)
+        .map_err(|e| Error::Internal(e.to_string().into()))?;
+    println!("{json}");
+
+    // same convention as git merge drivers
+    Ok(if conflicts.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    })
+}
+
+fn optimize(path: &Path, output: &Path) -> Result<ExitCode, Error> {
+    let mut map = read_map(path)?;
+    let report = optimize_map(&mut map)?;
+    if !report.is_empty() || path != output {
+        let mut buf = Vec::new();
+        map.save(&mut buf).map_err(|e| Error::Map(e.to_string()))?;
+        std::fs::write(output, buf).map_err(|e| Error::Internal(e.to_string().into()))?;
+    }
+
+    let json =
+        serde_json::to_string_pretty(&report).map_err(|e| Error::Internal(e.to_string().into()))?;
+    println!("{json}");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn run_command(command: &Command) -> ExitCode {
+    let res = match command {
+        Command::Diff { base, other } => diff(base, other),
+        Command::Merge {
+            base,
+            ours,
+            theirs,
+            output,
+        } => merge(base, ours, theirs, output.as_ref().unwrap_or(ours)),
+        Command::Optimize { map, output } => optimize(map, output.as_ref().unwrap_or(map)),
+    };
+
+    res.unwrap_or_else(|e| {
+        log::error!("{e}");
+        ExitCode::from(2)
+    })
+}
@@ -0,0 +1,342 @@
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use twmap::{CurveKind, Env, EnvPoint, Envelope, Layer, Position, TwMap, Volume};
+use vek::Rgba;
+
+use crate::error::Error;
+
+// Evaluation of envelopes, a port of DDNet's CRenderTools::RenderEvalEnvelope.
+// The interpolation is done on the raw fixed-point values of the map file like
+// DDNet does, then converted to the units of twmap (tiles, degrees, [0-1] colors).
+
+pub const MAX_ENV_SAMPLES: usize = 10_000;
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvelopeEval {
+    /// Times to evaluate at, in milliseconds.
+    pub times: Vec<f64>,
+    /// Or evaluate at every `step` ms from `start` to `end` (inclusive).
+    pub range: Option<TimeRange>,
+    /// Time offset of the user of the envelope (e.g. `position_env_offset` of a quad), in ms.
+    pub offset: i32,
+    /// The times are the game time. Envelopes that are not `synchronized` follow the
+    /// local time of the clients instead, which is the game time plus this offset.
+    pub local_time: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Position { x: f64, y: f64, rotation: f64 },
+    Color { r: f64, g: f64, b: f64, a: f64 },
+    Sound(f64),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnvSample {
+    pub time: f64,
+    pub value: EnvValue,
+}
+
+trait EnvChannels: Copy {
+    const CHANNELS: usize;
+    /// Fixed-point values as stored in the map file.
+    fn raw(&self) -> [i32; 4];
+    fn value(raw: [f64; 4]) -> EnvValue;
+}
+
+impl EnvChannels for Position {
+    const CHANNELS: usize = 3;
+    fn raw(&self) -> [i32; 4] {
+        [
+            self.offset.x.to_bits(),
+            self.offset.y.to_bits(),
+            self.rotation.to_bits(),
+            0,
+        ]
+    }
+    fn value(raw: [f64; 4]) -> EnvValue {
+        EnvValue::Position {
+            x: raw[0] / 32768.0,
+            y: raw[1] / 32768.0,
+            rotation: raw[2] / 1024.0,
+        }
+    }
+}
+
+impl EnvChannels for Rgba<fixed::types::I22F10> {
+    const CHANNELS: usize = 4;
+    fn raw(&self) -> [i32; 4] {
+        [self.r, self.g, self.b, self.a].map(|c| c.to_bits())
+    }
+    fn value(raw: [f64; 4]) -> EnvValue {
+        let [r, g, b, a] = raw.map(|c| c / 1024.0);
+        EnvValue::Color { r, g, b, a }
+    }
+}
+
+impl EnvChannels for Volume {
+    const CHANNELS: usize = 1;
+    fn raw(&self) -> [i32; 4] {
+        [self.0.to_bits(), 0, 0, 0]
+    }
+    fn value(raw: [f64; 4]) -> EnvValue {
+        EnvValue::Sound(raw[0] / 1024.0)
+    }
+}
+
+fn bezier(p0: f64, p1: f64, p2: f64, p3: f64, t: f64) -> f64 {
+    let u = 1.0 - t;
+    u * u * u * p0 + 3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t * p3
+}
+
+// solves x(t) = x for t, x being the time component of the bezier curve.
+fn solve_bezier(x: f64, p0: f64, p1: f64, p2: f64, p3: f64) -> f64 {
+    let x3 = -p0 + 3.0 * p1 - 3.0 * p2 + p3;
+    let x2 = 3.0 * p0 - 6.0 * p1 + 3.0 * p2;
+    let x1 = -3.0 * p0 + 3.0 * p1;
+    let x0 = p0 - x;
+    let in_range = |t: f64| (0.0..=1.0001).contains(&t);
+
+    if x3 == 0.0 && x2 == 0.0 {
+        // linear
+        if x1 == 0.0 {
+            0.0
+        } else {
+            -x0 / x1
+        }
+    } else if x3 == 0.0 {
+        // quadratic
+        let b = x1 / x2;
+        let c = x0 / x2;
+        if c == 0.0 {
+            return 0.0;
+        }
+        let sqrt_d = (b * b - 4.0 * c).sqrt();
+        let t = (-b + sqrt_d) / 2.0;
+        if in_range(t) {
+            t
+        } else {
+            (-b - sqrt_d) / 2.0
+        }
+    } else {
+        // cubic, with cardano's method
+        let a = x2 / x3;
+        let b = x1 / x3;
+        let c = x0 / x3;
+        let sub = a / 3.0;
+        let p = b / 3.0 - a * a / 9.0;
+        let q = (2.0 * a * a * a / 27.0 - a * b / 3.0 + c) / 2.0;
+        let d = q * q + p * p * p;
+
+        if d > 0.0 {
+            let s = d.sqrt();
+            (s - q).cbrt() - (s + q).cbrt() - sub
+        } else if d == 0.0 {
+            let s = (-q).cbrt();
+            let t = 2.0 * s - sub;
+            if in_range(t) {
+                t
+            } else {
+                -s - sub
+            }
+        } else {
+            let phi = (-q / (-(p * p * p)).sqrt()).acos() / 3.0;
+            let s = 2.0 * (-p).sqrt();
+            let t1 = s * phi.cos() - sub;
+            let t2 = -s * (phi + std::f64::consts::PI / 3.0).cos() - sub;
+            if in_range(t1) {
+                t1
+            } else if in_range(t2) {
+                t2
+            } else {
+                -s * (phi - std::f64::consts::PI / 3.0).cos() - sub
+            }
+        }
+    }
+}
+
+fn eval_points<T: EnvChannels>(points: &[EnvPoint<T>], time: f64) -> [f64; 4] {
+    let raw = |point: &EnvPoint<T>| point.content.raw().map(f64::from);
+
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return [0.0; 4];
+    };
+    if points.len() == 1 {
+        return raw(first);
+    }
+
+    // the envelope loops after its last point.
+    let time = if last.time > 0 {
+        time.rem_euclid(last.time as f64)
+    } else {
+        0.0
+    };
+
+    for (cur, next) in points.iter().zip(&points[1..]) {
+        if time < cur.time as f64 || time > next.time as f64 || next.time == cur.time {
+            continue;
+        }
+
+        let (t0, t1) = (cur.time as f64, next.time as f64);
+        let (v0, v1) = (raw(cur), raw(next));
+        let a = (time - t0) / (t1 - t0);
+
+        let a = match &cur.curve {
+            CurveKind::Step => 0.0,
+            CurveKind::Slow => a * a * a,
+            CurveKind::Fast => 1.0 - (1.0 - a).powi(3),
+            CurveKind::Smooth => -2.0 * a * a * a + 3.0 * a * a,
+            CurveKind::Bezier(out) => {
+                // the in-tangent of the next point is only known if it is a bezier point too.
+                let (in_x, in_y) = match &next.curve {
+                    CurveKind::Bezier(bezier) => (bezier.handle_l.x.raw(), bezier.handle_l.y.raw()),
+                    _ => ([0; 4], [0; 4]),
+                };
+                let (out_x, out_y) = (out.handle_r.x.raw(), out.handle_r.y.raw());
+
+                let mut res = [0.0; 4];
+                for c in 0..T::CHANNELS {
+                    // the handles cannot go past the points in time
+                    let p1x = (t0 + out_x[c] as f64).clamp(t0, t1);
+                    let p2x = (t1 + in_x[c] as f64).clamp(t0, t1);
+                    let p1y = v0[c] + out_y[c] as f64;
+                    let p2y = v1[c] + in_y[c] as f64;
+                    let a = solve_bezier(time, t0, p1x, p2x, t1).clamp(0.0, 1.0);
+                    res[c] = bezier(v0[c], p1y, p2y, v1[c], a);
+                }
+                return res;
+            }
+            CurveKind::Linear | CurveKind::Unknown(_) => a,
+        };
+
+        let mut res = [0.0; 4];
+        for c in 0..T::CHANNELS {
+            res[c] = v0[c] + (v1[c] - v0[c]) * a;
+        }
+        return res;
+    }
+
+    raw(last)
+}
+
+fn eval_env<T: EnvChannels>(env: &Env<T>, time: f64) -> EnvValue {
+    T::value(eval_points(&env.points, time))
+}
+
+pub fn eval_envelope(env: &Envelope, params: &EnvelopeEval) -> Result<Vec<EnvSample>, Error> {
+    let mut times = params.times.clone();
+
+    if let Some(range) = &params.range {
+        if range.step <= 0.0 || range.end < range.start {
+            return Err(Error::Invalid("envelope time range"));
+        }
+        let count = ((range.end - range.start) / range.step).floor() + 1.0;
+        if count + times.len() as f64 > MAX_ENV_SAMPLES as f64 {
+            return Err(Error::Invalid("number of envelope samples"));
+        }
+        times.extend((0..count as usize).map(|i| range.start + i as f64 * range.step));
+    }
+
+    if times.len() > MAX_ENV_SAMPLES {
+        return Err(Error::Invalid("number of envelope samples"));
+    }
+    if times.iter().any(|t| !t.is_finite()) {
+        return Err(Error::Invalid("envelope time"));
+    }
+
+    let synchronized = match env {
+        Envelope::Position(env) => env.synchronized,
+        Envelope::Color(env) => env.synchronized,
+        Envelope::Sound(env) => env.synchronized,
+    };
+
+    let samples = times
+        .into_iter()
+        .map(|time| {
+            let mut env_time = time + params.offset as f64;
+            if !synchronized {
+                env_time += params.local_time;
+            }
+            let value = match env {
+                Envelope::Position(env) => eval_env(env, env_time),
+                Envelope::Color(env) => eval_env(env, env_time),
+                Envelope::Sound(env) => eval_env(env, env_time),
+            };
+            EnvSample { time, value }
+        })
+        .collect();
+
+    Ok(samples)
+}
+
+/// Where an envelope is used, indices are (group, layer) and (group, layer, quad / source).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EnvelopeUsage {
+    pub tiles_color: Vec<(u16, u16)>,
+    pub quads_position: Vec<(u16, u16, u16)>,
+    pub quads_color: Vec<(u16, u16, u16)>,
+    pub sources_position: Vec<(u16, u16, u16)>,
+    pub sources_sound: Vec<(u16, u16, u16)>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeleteEnvelopeParams {
+    /// Also delete an envelope in use, its users are left without envelope.
+    pub force: bool,
+}
+
+fn env_usage(usage: &mut [EnvelopeUsage], env: Option<u16>) -> Option<&mut EnvelopeUsage> {
+    env.and_then(|env| usage.get_mut(env as usize))
+}
+
+/// The usage of each envelope of the map.
+pub fn envelopes_usage(map: &TwMap) -> Vec<EnvelopeUsage> {
+    let mut usage = vec![EnvelopeUsage::default(); map.envelopes.len()];
+
+    for (g, group) in map.groups.iter().enumerate() {
+        for (l, layer) in group.layers.iter().enumerate() {
+            let (g, l) = (g as u16, l as u16);
+            match layer {
+                Layer::Tiles(layer) => {
+                    if let Some(usage) = env_usage(&mut usage, layer.color_env) {
+                        usage.tiles_color.push((g, l));
+                    }
+                }
+                Layer::Quads(layer) => {
+                    for (q, quad) in layer.quads.iter().enumerate() {
+                        if let Some(usage) = env_usage(&mut usage, quad.position_env) {
+                            usage.quads_position.push((g, l, q as u16));
+                        }
+                        if let Some(usage) = env_usage(&mut usage, quad.color_env) {
+                            usage.quads_color.push((g, l, q as u16));
+                        }
+                    }
+                }
+                Layer::Sounds(layer) => {
+                    for (s, source) in layer.sources.iter().enumerate() {
+                        if let Some(usage) = env_usage(&mut usage, source.position_env) {
+                            usage.sources_position.push((g, l, s as u16));
+                        }
+                        if let Some(usage) = env_usage(&mut usage, source.sound_env) {
+                            usage.sources_sound.push((g, l, s as u16));
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    usage
+}
@@ -12,7 +12,7 @@ pub enum Error {
     LayerNotFound,
     QuadNotFound,
     AutomapperNotFound,
-    #[allow(unused)]
+    UploadNotFound,
     NotFound(&'static str),
 
     MaxEnvelopes,
@@ -21,6 +21,7 @@ pub enum Error {
     MaxGroups,
     MaxLayers,
     MaxQuads,
+    MaxSounds,
 
     InvalidImage,
     InvalidTiles,
@@ -37,10 +38,18 @@ pub enum Error {
     WrongTilesImage,
 
     ImageInUse,
+    ImageNameTaken,
+    ImageAlreadyEmbedded,
+    ImageAlreadyExternal,
+    ImageNotExternal,
     EnvelopeInUse,
 
     MapNameTaken,
     MapTooBig,
+    RenderTooBig,
+    UploadTooBig,
+    UploadIncomplete,
+    MaxUploads,
     MaxMaps,
     MaxPeers,
     UnsupportedMapType,
@@ -61,6 +70,9 @@ pub enum Error {
 
     // 403 forbidden
     DeletePhysicsGroup,
+    DuplicatePhysicsGroup,
+    CopyPhysicsLayer,
+    CopySoundsLayer,
     DeleteGameLayer,
     CreateGameLayer,
     CreatePhysicsLayerOutOfPhysicsGroup,
@@ -84,6 +96,7 @@ impl Display for Error {
             Error::LayerNotFound => write!(f, "layer not found"),
             Error::QuadNotFound => write!(f, "quad not found"),
             Error::AutomapperNotFound => write!(f, "automapper not found"),
+            Error::UploadNotFound => write!(f, "upload not found"),
             Error::NotFound(x) => write!(f, "{x} not found"),
             Error::MaxEnvelopes => write!(f, "maximum number of envelopes reached"),
             Error::MaxEnvPoints => write!(f, "maximum number of envelope points reached"),
@@ -91,6 +104,7 @@ impl Display for Error {
             Error::MaxGroups => write!(f, "maximum number of groups reached"),
             Error::MaxLayers => write!(f, "maximum number of layers reached"),
             Error::MaxQuads => write!(f, "maximum number of quads reached"),
+            Error::MaxSounds => write!(f, "maximum number of sounds reached"),
             Error::InvalidImage => write!(f, "invalid image"),
             Error::InvalidTiles => write!(f, "invalid tiles"),
             Error::InvalidMapName => write!(f, "invalid map name"),
@@ -103,9 +117,19 @@ impl Display for Error {
             Error::WrongLayerType => write!(f, "wrong layer type"),
             Error::WrongTilesImage => write!(f, "wrong tiles type"),
             Error::ImageInUse => write!(f, "image in use"),
+            Error::ImageNameTaken => write!(f, "another image has this name"),
+            Error::ImageAlreadyEmbedded => write!(f, "image is already embedded"),
+            Error::ImageAlreadyExternal => write!(f, "image is already external"),
+            Error::ImageNotExternal => {
+                write!(f, "image does not match a known external image")
+            }
             Error::EnvelopeInUse => write!(f, "envelope in use"),
             Error::MapNameTaken => write!(f, "map name already taken"),
             Error::MapTooBig => write!(f, "map size exceeds limit"),
+            Error::RenderTooBig => write!(f, "rendered image size exceeds limit"),
+            Error::UploadTooBig => write!(f, "upload size exceeds limit"),
+            Error::UploadIncomplete => write!(f, "upload is incomplete"),
+            Error::MaxUploads => write!(f, "maximum number of simultaneous uploads reached"),
             Error::MaxMaps => write!(f, "maximum number of maps reached"),
             Error::MaxPeers => write!(f, "maximum number of simultaneous connections reached"),
             Error::UnsupportedMapType => write!(f, "unsupported map type"),
@@ -121,6 +145,9 @@ impl Display for Error {
             Error::Automapper(x) => write!(f, "automapper error: {x}"),
             Error::BadRequest(x) => write!(f, "bad request: {x}"),
             Error::DeletePhysicsGroup => write!(f, "cannot delete the physics group"),
+            Error::DuplicatePhysicsGroup => write!(f, "cannot duplicate the physics group"),
+            Error::CopyPhysicsLayer => write!(f, "cannot copy physics layers"),
+            Error::CopySoundsLayer => write!(f, "cannot copy or duplicate sounds layers"),
             Error::DeleteGameLayer => write!(f, "cannot delete the game layer"),
             Error::CreateGameLayer => write!(f, "cannot create a second game layer"),
             Error::CreatePhysicsLayerOutOfPhysicsGroup => write!(
@@ -158,6 +185,7 @@ impl IntoResponse for Error {
             Error::LayerNotFound => StatusCode::NOT_FOUND,
             Error::QuadNotFound => StatusCode::NOT_FOUND,
             Error::AutomapperNotFound => StatusCode::NOT_FOUND,
+            Error::UploadNotFound => StatusCode::NOT_FOUND,
             Error::NotFound(_) => StatusCode::NOT_FOUND,
             Error::MaxEnvelopes => StatusCode::BAD_REQUEST,
             Error::MaxEnvPoints => StatusCode::BAD_REQUEST,
@@ -165,6 +193,7 @@ impl IntoResponse for Error {
             Error::MaxGroups => StatusCode::BAD_REQUEST,
             Error::MaxLayers => StatusCode::BAD_REQUEST,
             Error::MaxQuads => StatusCode::BAD_REQUEST,
+            Error::MaxSounds => StatusCode::BAD_REQUEST,
             Error::InvalidImage => StatusCode::BAD_REQUEST,
             Error::InvalidTiles => StatusCode::BAD_REQUEST,
             Error::InvalidMapName => StatusCode::BAD_REQUEST,
@@ -177,9 +206,17 @@ impl IntoResponse for Error {
             Error::WrongLayerType => StatusCode::BAD_REQUEST,
             Error::WrongTilesImage => StatusCode::BAD_REQUEST,
             Error::ImageInUse => StatusCode::BAD_REQUEST,
+            Error::ImageNameTaken => StatusCode::BAD_REQUEST,
+            Error::ImageAlreadyEmbedded => StatusCode::BAD_REQUEST,
+            Error::ImageAlreadyExternal => StatusCode::BAD_REQUEST,
+            Error::ImageNotExternal => StatusCode::BAD_REQUEST,
             Error::EnvelopeInUse => StatusCode::BAD_REQUEST,
             Error::MapNameTaken => StatusCode::BAD_REQUEST,
             Error::MapTooBig => StatusCode::BAD_REQUEST,
+            Error::RenderTooBig => StatusCode::BAD_REQUEST,
+            Error::UploadTooBig => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UploadIncomplete => StatusCode::BAD_REQUEST,
+            Error::MaxUploads => StatusCode::BAD_REQUEST,
             Error::MaxMaps => StatusCode::BAD_REQUEST,
             Error::MaxPeers => StatusCode::BAD_REQUEST,
             Error::UnsupportedMapType => StatusCode::BAD_REQUEST,
@@ -195,6 +232,9 @@ impl IntoResponse for Error {
             Error::Automapper(_) => StatusCode::BAD_REQUEST,
             Error::BadRequest(_) => StatusCode::BAD_REQUEST,
             Error::DeletePhysicsGroup => StatusCode::FORBIDDEN,
+            Error::DuplicatePhysicsGroup => StatusCode::FORBIDDEN,
+            Error::CopyPhysicsLayer => StatusCode::FORBIDDEN,
+            Error::CopySoundsLayer => StatusCode::FORBIDDEN,
             Error::DeleteGameLayer => StatusCode::FORBIDDEN,
             Error::CreateGameLayer => StatusCode::FORBIDDEN,
             Error::CreatePhysicsLayerOutOfPhysicsGroup => StatusCode::FORBIDDEN,
@@ -4,17 +4,37 @@ use cli::Cli;
 
 use server::Server;
 
+mod automap;
 mod base64;
 mod checks;
 pub mod cli;
+mod commands;
+mod envelope;
 mod error;
+mod library;
 mod map_cfg;
+mod map_copy;
+mod map_diff;
+mod map_merge;
+mod map_optimize;
+mod map_stats;
+mod map_sync;
+mod map_transform;
+mod mapres;
 mod protocol;
+mod quads_edit;
+mod render;
 mod room;
 pub mod router;
+mod rpp;
+mod rules;
 mod server;
+mod tiles_find;
+mod tiles_remap;
+mod tw_automapper;
 mod twmap_map_checks;
 mod twmap_map_edit;
+mod uploads;
 mod util;
 
 #[cfg(feature = "bridge")]
@@ -22,6 +42,7 @@ mod bridge;
 #[cfg(feature = "bridge")]
 mod bridge_router;
 
+pub use commands::run_command;
 use room::Room;
 
 pub fn create_server(cli: &Cli) -> std::io::Result<Server> {
@@ -0,0 +1,187 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    protocol::{AutomapperDiagnostic, AutomapperKind, Severity},
+    rpp::{rpp_diagnostics, Rpp},
+    rules::parse_rules,
+    tw_automapper::parse_tw_automapper,
+    util::{check_file_name, is_automapper},
+};
+
+// Automappers shared by all the maps of the server, in the --library directory.
+// Publishing an automapper adds a new version and keeps the previous ones, they are
+// stored as `<library>/<name>/<version>.<extension>`, e.g. `grass_main.rules/3.rules`.
+pub struct Library {
+    dir: Option<PathBuf>,
+    // versions are numbered by looking at the existing ones.
+    publish_lock: Mutex<()>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LibraryAutomapper {
+    pub name: String,
+    pub image: String,
+    pub kind: AutomapperKind,
+    /// In increasing order, the last one is the latest.
+    pub versions: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImportedAutomapper {
+    pub version: u32,
+    /// Warnings about the automapper in the map, e.g. a Rules++ file that was not compiled.
+    pub diagnostics: Vec<AutomapperDiagnostic>,
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::Internal(e.to_string().into())
+}
+
+fn check_name(name: &str) -> Result<AutomapperKind, Error> {
+    if !check_file_name(name) {
+        return Err(Error::InvalidFileName);
+    }
+    is_automapper(Path::new(name)).ok_or(Error::InvalidFileName)
+}
+
+fn versions(dir: &Path) -> Vec<u32> {
+    let mut versions: Vec<u32> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.path().file_stem()?.to_str()?.parse().ok())
+        .collect();
+    versions.sort_unstable();
+    versions
+}
+
+// automappers with errors cannot be used, they are not shared. Rules++ files can only
+// be checked by compiling them, when the server has rpp.
+async fn check_automapper(
+    name: &str,
+    kind: AutomapperKind,
+    file: &str,
+    rpp: Option<&Rpp>,
+) -> Result<(), Error> {
+    let diagnostics = match (kind, rpp) {
+        (AutomapperKind::DDNet, _) => parse_rules(name.to_owned(), file).1,
+        (AutomapperKind::Teeworlds, _) => parse_tw_automapper(file).1,
+        (AutomapperKind::RulesPP, Some(rpp)) => {
+            let stem = Path::new(name).file_stem().unwrap_or_default();
+            match rpp.compile_source(&stem.to_string_lossy(), file).await {
+                Ok(rules) => parse_rules(name.to_owned(), &rules).1,
+                Err(Error::Automapper(output)) => {
+                    let diagnostics = rpp_diagnostics(&output);
+                    if diagnostics.is_empty() {
+                        return Err(Error::Automapper(output));
+                    }
+                    diagnostics
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        (AutomapperKind::RulesPP, None) => vec![],
+    };
+    match diagnostics.iter().find(|d| d.severity == Severity::Error) {
+        Some(d) => Err(Error::Automapper(format!(
+            "line {}: {}",
+            d.span.line_start, d.msg
+        ))),
+        None => Ok(()),
+    }
+}
+
+impl Library {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Library {
+            dir,
+            publish_lock: Mutex::new(()),
+        }
+    }
+
+    fn dir(&self) -> Result<&Path, Error> {
+        self.dir
+            .as_deref()
+            .ok_or(Error::NotFound("automapper library"))
+    }
+
+    fn version_path(dir: &Path, name: &str, version: u32) -> PathBuf {
+        let ext = Path::new(name).extension().unwrap_or_default();
+        dir.join(name).join(version.to_string()).with_extension(ext)
+    }
+
+    pub fn list(&self) -> Result<Vec<LibraryAutomapper>, Error> {
+        let dir = self.dir()?;
+        let Ok(read_dir) = std::fs::read_dir(dir) else {
+            return Ok(vec![]);
+        };
+
+        let mut automappers: Vec<_> = read_dir
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_str()?.to_owned();
+                let kind = check_name(&name).ok()?;
+                let versions = versions(&e.path());
+                if versions.is_empty() {
+                    return None;
+                }
+                let image = name.rsplit_once('.')?.0.to_owned();
+                Some(LibraryAutomapper {
+                    name,
+                    image,
+                    kind,
+                    versions,
+                })
+            })
+            .collect();
+        automappers.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(automappers)
+    }
+
+    /// The version of the automapper and its content, the latest if not given.
+    pub fn get(&self, name: &str, version: Option<u32>) -> Result<(u32, String), Error> {
+        check_name(name)?;
+        let dir = self.dir()?;
+        let version = match version {
+            Some(version) => version,
+            None => *versions(&dir.join(name))
+                .last()
+                .ok_or(Error::AutomapperNotFound)?,
+        };
+        let file = std::fs::read_to_string(Self::version_path(dir, name, version))
+            .map_err(|_| Error::AutomapperNotFound)?;
+        Ok((version, file))
+    }
+
+    /// Adds a new version of the automapper and returns it. Nothing is added if the
+    /// latest version is the same.
+    pub async fn publish(&self, name: &str, file: &str, rpp: Option<&Rpp>) -> Result<u32, Error> {
+        let kind = check_name(name)?;
+        // the lock is only taken once the file is compiled, compiling may take a while.
+        check_automapper(name, kind, file, rpp).await?;
+        let dir = self.dir()?;
+
+        let _lock = self.publish_lock.lock().unwrap();
+        let am_dir = dir.join(name);
+        let latest = versions(&am_dir).last().copied();
+
+        if let Some(latest) = latest {
+            let path = Self::version_path(dir, name, latest);
+            if std::fs::read_to_string(path).is_ok_and(|f| f == file) {
+                return Ok(latest);
+            }
+        }
+
+        let version = latest.map_or(1, |v| v + 1);
+        std::fs::create_dir_all(&am_dir).map_err(io_error)?;
+        std::fs::write(Self::version_path(dir, name, version), file).map_err(io_error)?;
+        log::info!("published {name} version {version} in the automapper library");
+        Ok(version)
+    }
+}
@@ -1,8 +1,8 @@
-use std::sync::Arc;
+use std::{process::ExitCode, sync::Arc};
 
 use clap::Parser;
 
-use twwe_server::{cli::Cli, create_server, router::Router};
+use twwe_server::{cli::Cli, create_server, router::Router, run_command};
 
 #[tokio::main]
 async fn run_server(args: Cli) {
@@ -16,10 +16,15 @@ async fn run_server(args: Cli) {
     router.run(&args).await;
 }
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     let args = Cli::parse();
 
+    if let Some(command) = &args.command {
+        return run_command(command);
+    }
+
     run_server(args);
+    ExitCode::SUCCESS
 }
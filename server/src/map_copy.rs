@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use twmap::{Envelope, Group, Image, Layer, Sound, TwMap};
+
+use crate::{error::Error, util::MAX_IMAGES};
+
+// Copy of a group, layer, image or envelope from a map into another. The images,
+// envelopes and sounds used by a copied group or layer are imported along: identical
+// ones already in the target map are reused, the others are added under a free name.
+// The copy is done in two steps so that the two maps are never locked together.
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CopyReport {
+    /// Index of the new group, image or envelope, or (group, layer) index of the new layer.
+    pub index: Vec<u16>,
+    /// Indices of the images, envelopes and sounds added to the target map.
+    pub images: Vec<u16>,
+    pub envelopes: Vec<u16>,
+    pub sounds: Vec<u16>,
+}
+
+enum Item {
+    Group(Group),
+    /// The layer and the index of the group to add it to.
+    Layer(Layer, u16),
+    Image(u16),
+    Envelope(u16),
+}
+
+#[derive(Clone, Copy)]
+enum Index {
+    Image,
+    Envelope,
+    Sound,
+}
+
+/// A copied item, with the images, envelopes and sounds it uses.
+pub struct Fragment {
+    item: Item,
+    images: BTreeMap<u16, Image>,
+    envelopes: BTreeMap<u16, Envelope>,
+    sounds: BTreeMap<u16, Sound>,
+}
+
+fn edit_indices(layer: &mut Layer, f: &mut impl FnMut(Index, &mut Option<u16>)) {
+    match layer {
+        Layer::Tiles(layer) => {
+            f(Index::Image, &mut layer.image);
+            f(Index::Envelope, &mut layer.color_env);
+        }
+        Layer::Quads(layer) => {
+            f(Index::Image, &mut layer.image);
+            for quad in &mut layer.quads {
+                f(Index::Envelope, &mut quad.position_env);
+                f(Index::Envelope, &mut quad.color_env);
+            }
+        }
+        Layer::Sounds(layer) => {
+            f(Index::Sound, &mut layer.sound);
+            for source in &mut layer.sources {
+                f(Index::Envelope, &mut source.position_env);
+                f(Index::Envelope, &mut source.sound_env);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn check_layer(layer: &Layer) -> Result<(), Error> {
+    if layer.kind().is_physics_layer() {
+        Err(Error::CopyPhysicsLayer)
+    } else if let Layer::Sounds(_) = layer {
+        // the clients do not load sounds layers, the copy could not be sent to the peers.
+        Err(Error::CopySoundsLayer)
+    } else {
+        Ok(())
+    }
+}
+
+// the name itself if not taken, otherwise the name with the first free suffix.
+fn free_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    if name.is_empty() || !taken(name) {
+        return name.to_owned();
+    }
+    (2..)
+        .map(|i| format!("{name}_{i}"))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+impl Fragment {
+    fn new(map: &TwMap, mut item: Item) -> Result<Self, Error> {
+        let mut images = BTreeMap::new();
+        let mut envelopes = BTreeMap::new();
+        let mut sounds = BTreeMap::new();
+
+        let layers = match &mut item {
+            Item::Group(group) => &mut group.layers[..],
+            Item::Layer(layer, _) => std::slice::from_mut(layer),
+            Item::Image(_) | Item::Envelope(_) => &mut [],
+        };
+
+        let mut missing = false;
+        for layer in layers {
+            edit_indices(layer, &mut |kind, index| {
+                let Some(i) = *index else {
+                    return;
+                };
+                let found = match kind {
+                    Index::Image => map.images.get(i as usize).map(|image| {
+                        images.entry(i).or_insert_with(|| image.clone());
+                    }),
+                    Index::Envelope => map.envelopes.get(i as usize).map(|env| {
+                        envelopes.entry(i).or_insert_with(|| env.clone());
+                    }),
+                    Index::Sound => map.sounds.get(i as usize).map(|sound| {
+                        sounds.entry(i).or_insert_with(|| sound.clone());
+                    }),
+                };
+                missing |= found.is_none();
+            });
+        }
+
+        if missing {
+            return Err(Error::Map("invalid index in copied layer".to_owned()));
+        }
+
+        Ok(Fragment {
+            item,
+            images,
+            envelopes,
+            sounds,
+        })
+    }
+
+    pub fn group(map: &TwMap, group_index: u16) -> Result<Self, Error> {
+        let group = map
+            .groups
+            .get(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .clone();
+
+        if group.is_physics_group() {
+            return Err(Error::CopyPhysicsLayer);
+        }
+        group.layers.iter().try_for_each(check_layer)?;
+
+        Self::new(map, Item::Group(group))
+    }
+
+    pub fn layer(
+        map: &TwMap,
+        group_index: u16,
+        layer_index: u16,
+        target_group: u16,
+    ) -> Result<Self, Error> {
+        let layer = map
+            .groups
+            .get(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?
+            .clone();
+
+        check_layer(&layer)?;
+
+        Self::new(map, Item::Layer(layer, target_group))
+    }
+
+    pub fn image(map: &TwMap, image_index: u16) -> Result<Self, Error> {
+        let image = map
+            .images
+            .get(image_index as usize)
+            .ok_or(Error::ImageNotFound)?;
+
+        let mut fragment = Self::new(map, Item::Image(image_index))?;
+        fragment.images.insert(image_index, image.clone());
+        Ok(fragment)
+    }
+
+    pub fn envelope(map: &TwMap, env_index: u16) -> Result<Self, Error> {
+        let env = map
+            .envelopes
+            .get(env_index as usize)
+            .ok_or(Error::EnvelopeNotFound)?;
+
+        let mut fragment = Self::new(map, Item::Envelope(env_index))?;
+        fragment.envelopes.insert(env_index, env.clone());
+        Ok(fragment)
+    }
+
+    /// Adds the fragment to the map. The map is left unchanged on error.
+    pub fn paste(self, map: &mut TwMap) -> Result<CopyReport, Error> {
+        let target_group = match &self.item {
+            Item::Layer(_, group) => Some(*group as usize),
+            _ => None,
+        };
+        let layers_len = match target_group {
+            Some(g) => map.groups.get(g).ok_or(Error::GroupNotFound)?.layers.len(),
+            None => 0,
+        };
+        let lens = (
+            map.groups.len(),
+            map.images.len(),
+            map.envelopes.len(),
+            map.sounds.len(),
+        );
+
+        let res = self.paste_unchecked(map).and_then(|report| {
+            map.check().map_err(|e| Error::Map(e.to_string()))?;
+            Ok(report)
+        });
+
+        if res.is_err() {
+            // the items are only added at the end, truncating undoes the paste.
+            map.groups.truncate(lens.0);
+            map.images.truncate(lens.1);
+            map.envelopes.truncate(lens.2);
+            map.sounds.truncate(lens.3);
+            if let Some(g) = target_group {
+                map.groups[g].layers.truncate(layers_len);
+            }
+        }
+
+        res
+    }
+
+    fn paste_unchecked(self, map: &mut TwMap) -> Result<CopyReport, Error> {
+        let mut report = CopyReport::default();
+
+        let mut images = BTreeMap::new();
+        for (i, image) in self.images {
+            images.insert(i, import_image(map, image, &mut report.images)?);
+        }
+        let mut envelopes = BTreeMap::new();
+        for (i, env) in self.envelopes {
+            envelopes.insert(i, import_envelope(map, env, &mut report.envelopes)?);
+        }
+        let mut sounds = BTreeMap::new();
+        for (i, sound) in self.sounds {
+            sounds.insert(i, import_sound(map, sound, &mut report.sounds)?);
+        }
+
+        let mut remap = |kind, index: &mut Option<u16>| {
+            let indices = match kind {
+                Index::Image => &images,
+                Index::Envelope => &envelopes,
+                Index::Sound => &sounds,
+            };
+            *index = index.and_then(|i| indices.get(&i).copied());
+        };
+
+        let layers_count: usize = map.groups.iter().map(|g| g.layers.len()).sum();
+
+        report.index = match self.item {
+            Item::Group(mut group) => {
+                if map.groups.len() == u16::MAX as usize {
+                    return Err(Error::MaxGroups);
+                }
+                if layers_count + group.layers.len() > u16::MAX as usize {
+                    return Err(Error::MaxLayers);
+                }
+                for layer in &mut group.layers {
+                    edit_indices(layer, &mut remap);
+                }
+                map.groups.push(group);
+                vec![map.groups.len() as u16 - 1]
+            }
+            Item::Layer(mut layer, g) => {
+                if layers_count == u16::MAX as usize {
+                    return Err(Error::MaxLayers);
+                }
+                edit_indices(&mut layer, &mut remap);
+                let group = map.groups.get_mut(g as usize).ok_or(Error::GroupNotFound)?;
+                group.layers.push(layer);
+                vec![g, group.layers.len() as u16 - 1]
+            }
+            Item::Image(i) => vec![images[&i]],
+            Item::Envelope(i) => vec![envelopes[&i]],
+        };
+
+        Ok(report)
+    }
+}
+
+fn import_image(map: &mut TwMap, mut image: Image, added: &mut Vec<u16>) -> Result<u16, Error> {
+    // embedded images are compared without their name, which may have been changed by a previous copy.
+    let same = |other: &Image| match (other, &image) {
+        (Image::Embedded(other), Image::Embedded(image)) => other.image == image.image,
+        (other, image) => other == image,
+    };
+    if let Some(i) = map.images.iter().position(same) {
+        return Ok(i as u16);
+    }
+
+    let name = free_name(image.name(), |name| {
+        map.images.iter().any(|other| other.name() == name)
+    });
+    // the name of an external image is what it refers to.
+    if let Image::External(_) = image {
+        if name != *image.name() {
+            return Err(Error::ImageNameTaken);
+        }
+    }
+    *image.name_mut() = name;
+
+    if map.images.len() >= MAX_IMAGES {
+        return Err(Error::MaxImages);
+    }
+
+    added.push(map.images.len() as u16);
+    map.images.push(image);
+    Ok(map.images.len() as u16 - 1)
+}
+
+fn import_envelope(map: &mut TwMap, mut env: Envelope, added: &mut Vec<u16>) -> Result<u16, Error> {
+    let same = |other: &Envelope| {
+        let mut other = other.clone();
+        *other.name_mut() = env.name().clone();
+        other == env
+    };
+    if let Some(i) = map.envelopes.iter().position(same) {
+        return Ok(i as u16);
+    }
+
+    *env.name_mut() = free_name(env.name(), |name| {
+        map.envelopes.iter().any(|other| other.name() == name)
+    });
+
+    if map.envelopes.len() == u16::MAX as usize {
+        return Err(Error::MaxEnvelopes);
+    }
+
+    added.push(map.envelopes.len() as u16);
+    map.envelopes.push(env);
+    Ok(map.envelopes.len() as u16 - 1)
+}
+
+fn import_sound(map: &mut TwMap, mut sound: Sound, added: &mut Vec<u16>) -> Result<u16, Error> {
+    if let Some(i) = map.sounds.iter().position(|other| other.data == sound.data) {
+        return Ok(i as u16);
+    }
+
+    sound.name = free_name(&sound.name, |name| {
+        map.sounds.iter().any(|other| other.name == name)
+    });
+
+    if map.sounds.len() == u16::MAX as usize {
+        return Err(Error::MaxSounds);
+    }
+
+    added.push(map.sounds.len() as u16);
+    map.sounds.push(sound);
+    Ok(map.sounds.len() as u16 - 1)
+}
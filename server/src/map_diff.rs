@@ -0,0 +1,428 @@
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use twmap::{AnyTile, Layer, LayerKind, TwMap};
+use vek::{Extent2, Rect};
+
+// Semantic diff between two maps.
+// Items (images, envelopes, groups, layers, quads) are paired with the items of
+// the other map with an LCS alignment on their identity (e.g. name and kind),
+// the unpaired items in-between are then matched positionally. This is robust
+// to insertions and deletions in the middle of a list, which an index-by-index
+// comparison is not.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexDiff {
+    pub change: Change,
+    pub base: Option<u16>,
+    pub other: Option<u16>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemDiff {
+    #[serde(flatten)]
+    pub index: IndexDiff,
+    pub name: String,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayerDiff {
+    #[serde(flatten)]
+    pub item: ItemDiff,
+    pub kind: String,
+    pub properties: bool,
+    pub resized: Option<(Extent2<usize>, Extent2<usize>)>,
+    pub tiles: Vec<Rect<u32, u32>>,
+    pub quads: Vec<IndexDiff>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupDiff {
+    #[serde(flatten)]
+    pub item: ItemDiff,
+    pub properties: bool,
+    pub layers: Vec<LayerDiff>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MapDiff {
+    pub info: bool,
+    pub images: Vec<ItemDiff>,
+    pub envelopes: Vec<ItemDiff>,
+    pub groups: Vec<GroupDiff>,
+}
+
+impl MapDiff {
+    pub fn is_empty(&self) -> bool {
+        !self.info && self.images.is_empty() && self.envelopes.is_empty() && self.groups.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Pairing {
+    Both(usize, usize),
+    Base(usize),
+    Other(usize),
+}
+
+// above this many cells the LCS table is not computed and the items that differ
+// after trimming the common prefix and suffix are paired positionally.
+const MAX_LCS_CELLS: usize = 1 << 22;
+
+/// Pairs the items of `base` and `other`. `same` tells whether two items are the
+/// same entity (possibly modified), `compatible` whether two non-identical items
+/// may still be considered a modification of one another.
+pub(crate) fn align<T>(
+    base: &[T],
+    other: &[T],
+    same: impl Fn(&T, &T) -> bool,
+    compatible: impl Fn(&T, &T) -> bool,
+) -> Vec<Pairing> {
+    let prefix = base
+        .iter()
+        .zip(other)
+        .take_while(|(a, b)| same(a, b))
+        .count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(a, b)| same(a, b))
+        .count();
+
+    let base_mid = &base[prefix..base.len() - suffix];
+    let other_mid = &other[prefix..other.len() - suffix];
+    let (n, m) = (base_mid.len(), other_mid.len());
+
+    // anchors in the middle section, in increasing order
+    let mut anchors = Vec::new();
+
+    if n > 0 && m > 0 && (n + 1) * (m + 1) <= MAX_LCS_CELLS {
+        // lcs[i][j] = length of the LCS of base_mid[i..] and other_mid[j..]
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        let at = |i: usize, j: usize| i * (m + 1) + j;
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[at(i, j)] = if same(&base_mid[i], &other_mid[j]) {
+                    lcs[at(i + 1, j + 1)] + 1
+                } else {
+                    lcs[at(i + 1, j)].max(lcs[at(i, j + 1)])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if same(&base_mid[i], &other_mid[j]) {
+                anchors.push((i, j));
+                i += 1;
+                j += 1;
+            } else if lcs[at(i + 1, j)] >= lcs[at(i, j + 1)] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+
+    anchors.push((n, m)); // sentinel
+
+    let mut res: Vec<_> = (0..prefix).map(|i| Pairing::Both(i, i)).collect();
+
+    let (mut i, mut j) = (0, 0);
+    for (ai, aj) in anchors {
+        // gap between the previous anchor and this one
+        while i < ai && j < aj && compatible(&base_mid[i], &other_mid[j]) {
+            res.push(Pairing::Both(prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        }
+        res.extend((i..ai).map(|i| Pairing::Base(prefix + i)));
+        res.extend((j..aj).map(|j| Pairing::Other(prefix + j)));
+
+        if ai < n {
+            res.push(Pairing::Both(prefix + ai, prefix + aj));
+        }
+        i = ai + 1;
+        j = aj + 1;
+    }
+
+    res.extend(
+        (0..suffix).map(|k| Pairing::Both(base.len() - suffix + k, other.len() - suffix + k)),
+    );
+
+    res
+}
+
+fn index_diff(pairing: Pairing) -> IndexDiff {
+    match pairing {
+        Pairing::Both(i, j) => IndexDiff {
+            change: Change::Modified,
+            base: Some(i as u16),
+            other: Some(j as u16),
+        },
+        Pairing::Base(i) => IndexDiff {
+            change: Change::Removed,
+            base: Some(i as u16),
+            other: None,
+        },
+        Pairing::Other(j) => IndexDiff {
+            change: Change::Added,
+            base: None,
+            other: Some(j as u16),
+        },
+    }
+}
+
+/// Returns the list of changed items, paired with the items of the other list.
+fn diff_list<'a, T: PartialEq>(
+    base: &'a [T],
+    other: &'a [T],
+    same: impl Fn(&T, &T) -> bool,
+    compatible: impl Fn(&T, &T) -> bool,
+) -> impl Iterator<Item = (Pairing, Option<&'a T>, Option<&'a T>)> {
+    align(base, other, same, compatible)
+        .into_iter()
+        .filter_map(|p| match p {
+            Pairing::Both(i, j) => {
+                (base[i] != other[j]).then_some((p, Some(&base[i]), Some(&other[j])))
+            }
+            Pairing::Base(i) => Some((p, Some(&base[i]), None)),
+            Pairing::Other(j) => Some((p, None, Some(&other[j]))),
+        })
+}
+
+pub(crate) fn layer_kind_name(kind: LayerKind) -> &'static str {
+    match kind {
+        LayerKind::Game => "game",
+        LayerKind::Tiles => "tiles",
+        LayerKind::Quads => "quads",
+        LayerKind::Front => "front",
+        LayerKind::Tele => "tele",
+        LayerKind::Speedup => "speedup",
+        LayerKind::Switch => "switch",
+        LayerKind::Tune => "tune",
+        LayerKind::Sounds => "sounds",
+        LayerKind::Invalid(_) => "invalid",
+    }
+}
+
+pub(crate) fn same_group(a: &twmap::Group, b: &twmap::Group) -> bool {
+    a.name == b.name
+}
+
+pub(crate) fn group_properties_eq(a: &twmap::Group, b: &twmap::Group) -> bool {
+    a.name == b.name
+        && a.offset == b.offset
+        && a.parallax == b.parallax
+        && a.clipping == b.clipping
+        && a.clip == b.clip
+}
+
+pub(crate) fn same_layer(a: &Layer, b: &Layer) -> bool {
+    a.kind() == b.kind() && a.name() == b.name()
+}
+
+pub(crate) fn layer_properties_eq(a: &Layer, b: &Layer) -> bool {
+    match (a, b) {
+        (Layer::Tiles(a), Layer::Tiles(b)) => {
+            a.name == b.name
+                && a.detail == b.detail
+                && a.color == b.color
+                && a.color_env == b.color_env
+                && a.color_env_offset == b.color_env_offset
+                && a.image == b.image
+                && a.automapper_config == b.automapper_config
+        }
+        (Layer::Quads(a), Layer::Quads(b)) => {
+            a.name == b.name && a.detail == b.detail && a.image == b.image
+        }
+        (Layer::Sounds(a), Layer::Sounds(b)) => {
+            a.name == b.name && a.detail == b.detail && a.sound == b.sound
+        }
+        _ => a.kind() == b.kind(),
+    }
+}
+
+pub(crate) fn same_envelope(a: &twmap::Envelope, b: &twmap::Envelope) -> bool {
+    same_envelope_type(a, b) && a.name() == b.name()
+}
+
+pub(crate) fn same_envelope_type(a: &twmap::Envelope, b: &twmap::Envelope) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+pub(crate) fn same_image(a: &twmap::Image, b: &twmap::Image) -> bool {
+    a.name() == b.name()
+}
+
+/// Returns a grid (h, w) of the cells that differ between both tilemaps. Tilemaps
+/// of different sizes are compared as if the smallest one was extended with
+/// default tiles.
+pub(crate) fn changed_tiles<T: AnyTile>(base: &Array2<T>, other: &Array2<T>) -> Array2<bool> {
+    let h = base.nrows().max(other.nrows());
+    let w = base.ncols().max(other.ncols());
+    Array2::from_shape_fn((h, w), |pos| {
+        base.get(pos).copied().unwrap_or_default() != other.get(pos).copied().unwrap_or_default()
+    })
+}
+
+/// Bounding rectangles of the 8-connected regions of changed cells.
+pub(crate) fn changed_rects(changed: &Array2<bool>) -> Vec<Rect<u32, u32>> {
+    let (h, w) = changed.dim();
+    let mut visited = Array2::from_elem((h, w), false);
+    let mut rects = Vec::new();
+    let mut stack = Vec::new();
+
+    for ((y, x), &c) in changed.indexed_iter() {
+        if !c || visited[(y, x)] {
+            continue;
+        }
+
+        let (mut x1, mut y1, mut x2, mut y2) = (x, y, x, y);
+        visited[(y, x)] = true;
+        stack.push((y, x));
+
+        while let Some((y, x)) = stack.pop() {
+            x1 = x1.min(x);
+            y1 = y1.min(y);
+            x2 = x2.max(x);
+            y2 = y2.max(y);
+
+            for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                    if changed[(ny, nx)] && !visited[(ny, nx)] {
+                        visited[(ny, nx)] = true;
+                        stack.push((ny, nx));
+                    }
+                }
+            }
+        }
+
+        rects.push(Rect::new(
+            x1 as u32,
+            y1 as u32,
+            (x2 - x1 + 1) as u32,
+            (y2 - y1 + 1) as u32,
+        ));
+    }
+
+    rects
+}
+
+fn diff_tiles(base: &Layer, other: &Layer) -> Vec<Rect<u32, u32>> {
+    macro_rules! rects {
+        ($a:ident, $b:ident) => {
+            changed_rects(&changed_tiles($a.tiles.unwrap_ref(), $b.tiles.unwrap_ref()))
+        };
+    }
+
+    match (base, other) {
+        (Layer::Game(a), Layer::Game(b)) => rects!(a, b),
+        (Layer::Tiles(a), Layer::Tiles(b)) => rects!(a, b),
+        (Layer::Front(a), Layer::Front(b)) => rects!(a, b),
+        (Layer::Tele(a), Layer::Tele(b)) => rects!(a, b),
+        (Layer::Speedup(a), Layer::Speedup(b)) => rects!(a, b),
+        (Layer::Switch(a), Layer::Switch(b)) => rects!(a, b),
+        (Layer::Tune(a), Layer::Tune(b)) => rects!(a, b),
+        _ => Vec::new(),
+    }
+}
+
+fn diff_layer(pairing: Pairing, base: Option<&Layer>, other: Option<&Layer>) -> LayerDiff {
+    let layer = other.or(base).unwrap();
+    let mut diff = LayerDiff {
+        item: ItemDiff {
+            index: index_diff(pairing),
+            name: layer.name().to_owned(),
+        },
+        kind: layer_kind_name(layer.kind()).to_owned(),
+        properties: false,
+        resized: None,
+        tiles: Vec::new(),
+        quads: Vec::new(),
+    };
+
+    if let (Some(base), Some(other)) = (base, other) {
+        diff.properties = !layer_properties_eq(base, other);
+
+        if let (Some(a), Some(b)) = (base.shape(), other.shape()) {
+            if a != b {
+                diff.resized = Some((a, b));
+            }
+        }
+
+        diff.tiles = diff_tiles(base, other);
+
+        if let (Layer::Quads(a), Layer::Quads(b)) = (base, other) {
+            diff.quads = diff_list(&a.quads, &b.quads, |a, b| a == b, |_, _| true)
+                .map(|(p, _, _)| index_diff(p))
+                .collect();
+        }
+    }
+
+    diff
+}
+
+fn diff_group(
+    pairing: Pairing,
+    base: Option<&twmap::Group>,
+    other: Option<&twmap::Group>,
+) -> GroupDiff {
+    let group = other.or(base).unwrap();
+    let mut diff = GroupDiff {
+        item: ItemDiff {
+            index: index_diff(pairing),
+            name: group.name.to_owned(),
+        },
+        properties: false,
+        layers: Vec::new(),
+    };
+
+    if let (Some(base), Some(other)) = (base, other) {
+        diff.properties = !group_properties_eq(base, other);
+        diff.layers = diff_list(&base.layers, &other.layers, same_layer, |a, b| {
+            a.kind() == b.kind()
+        })
+        .map(|(p, a, b)| diff_layer(p, a, b))
+        .collect();
+    }
+
+    diff
+}
+
+/// Computes the changes needed to go from `base` to `other`. Both maps must be loaded.
+pub fn diff_maps(base: &TwMap, other: &TwMap) -> MapDiff {
+    let named = |p: Pairing, name: &str| ItemDiff {
+        index: index_diff(p),
+        name: name.to_owned(),
+    };
+
+    MapDiff {
+        info: base.info != other.info,
+        images: diff_list(&base.images, &other.images, same_image, |_, _| true)
+            .map(|(p, a, b)| named(p, b.or(a).unwrap().name()))
+            .collect(),
+        envelopes: diff_list(
+            &base.envelopes,
+            &other.envelopes,
+            same_envelope,
+            same_envelope_type,
+        )
+        .map(|(p, a, b)| named(p, b.or(a).unwrap().name()))
+        .collect(),
+        groups: diff_list(&base.groups, &other.groups, same_group, |_, _| true)
+            .map(|(p, a, b)| diff_group(p, a, b))
+            .collect(),
+    }
+}
@@ -0,0 +1,630 @@
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use twmap::{AnyTile, Layer, TwMap};
+use vek::Rect;
+
+use crate::{
+    base64::Base64,
+    map_diff::{
+        align, changed_rects, group_properties_eq, layer_properties_eq, same_envelope,
+        same_envelope_type, same_group, same_image, same_layer, Pairing,
+    },
+};
+
+// Three-way merge of maps.
+// The items of ours and theirs are paired with the items of base the same way
+// the diff does. An item changed on one side only takes that side's version,
+// an item changed on both sides is merged recursively (groups -> layers -> tiles
+// and quads) and what cannot be merged is reported as a conflict. Conflicts are
+// resolved in favor of ours, except deleted vs. edited items which are kept.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+// Location of a conflict. Indices are those of the merged map.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "item")]
+pub enum ConflictItem {
+    Info { field: String },
+    Image { image: u16 },
+    Envelope { envelope: u16 },
+    Sound { sound: u16 },
+    Group { group: u16 },
+    Layer { group: u16, layer: u16 },
+    Quad { group: u16, layer: u16, quad: u16 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "conflict")]
+pub enum ConflictKind {
+    /// Both sides edited the item differently, ours was kept.
+    Edited,
+    /// One side deleted the item while the other edited it, the edited one was kept.
+    DeletedEdited { deleted_by: Side },
+    /// Both sides changed the same tiles differently, ours were kept.
+    Tiles { rects: Vec<Rect<u32, u32>> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Conflict {
+    #[serde(flatten)]
+    pub item: ConflictItem,
+    #[serde(flatten)]
+    pub kind: ConflictKind,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MergedMap {
+    pub map: Base64,
+    pub conflicts: Vec<Conflict>,
+}
+
+enum Slot {
+    Base(usize, Option<usize>, Option<usize>),
+    Ours(usize),
+    Theirs(usize),
+}
+
+// Orders the items of the 3 lists: base items in the order of ours, with the items
+// added by ours at their position and the items added by theirs after the
+// preceding base item in theirs.
+fn slots<T>(
+    base: &[T],
+    ours: &[T],
+    theirs: &[T],
+    same: &impl Fn(&T, &T) -> bool,
+    compatible: &impl Fn(&T, &T) -> bool,
+) -> Vec<Slot> {
+    let mut base_theirs = vec![None; base.len()];
+    let mut theirs_added = Vec::new();
+    let mut anchor = None;
+
+    for p in align(base, theirs, same, compatible) {
+        match p {
+            Pairing::Both(b, t) => {
+                base_theirs[b] = Some(t);
+                anchor = Some(b);
+            }
+            Pairing::Base(b) => anchor = Some(b),
+            Pairing::Other(t) => theirs_added.push((anchor, t)),
+        }
+    }
+
+    let mut res = Vec::new();
+    let mut theirs_added = theirs_added.into_iter().peekable();
+    let mut flush = |res: &mut Vec<Slot>, anchor: Option<usize>| {
+        while let Some((_, t)) = theirs_added.next_if(|(a, _)| *a == anchor) {
+            res.push(Slot::Theirs(t));
+        }
+    };
+
+    flush(&mut res, None);
+
+    for p in align(base, ours, same, compatible) {
+        match p {
+            Pairing::Both(b, o) => {
+                res.push(Slot::Base(b, Some(o), base_theirs[b]));
+                flush(&mut res, Some(b));
+            }
+            Pairing::Base(b) => {
+                res.push(Slot::Base(b, None, base_theirs[b]));
+                flush(&mut res, Some(b));
+            }
+            Pairing::Other(o) => res.push(Slot::Ours(o)),
+        }
+    }
+
+    res
+}
+
+struct Merged<T> {
+    items: Vec<T>,
+    // index in the merged list of the items of each list
+    base: Vec<Option<usize>>,
+    ours: Vec<Option<usize>>,
+    theirs: Vec<Option<usize>>,
+    deleted_edited: Vec<(usize, Side)>,
+    // items deleted on one side and unchanged on the other, kept with `keep_deleted`
+    deleted: Vec<usize>,
+}
+
+/// `merge(base, ours, theirs, i)` merges an item edited on both sides that will be at
+/// index `i`. With `keep_deleted`, items deleted on one side are kept so the caller can
+/// remove them later.
+fn merge_items<T: Clone + PartialEq>(
+    base: &[T],
+    ours: &[T],
+    theirs: &[T],
+    same: impl Fn(&T, &T) -> bool,
+    compatible: impl Fn(&T, &T) -> bool,
+    keep_deleted: bool,
+    mut merge: impl FnMut(&T, &T, &T, usize) -> T,
+) -> Merged<T> {
+    let mut res = Merged {
+        items: Vec::new(),
+        base: vec![None; base.len()],
+        ours: vec![None; ours.len()],
+        theirs: vec![None; theirs.len()],
+        deleted_edited: Vec::new(),
+        deleted: Vec::new(),
+    };
+
+    let slots = slots(base, ours, theirs, &same, &compatible);
+
+    // items added identically on both sides are only added once
+    let ours_added: Vec<_> = slots
+        .iter()
+        .filter_map(|s| match s {
+            Slot::Ours(o) => Some(*o),
+            _ => None,
+        })
+        .collect();
+    let mut duplicates = Vec::new();
+
+    for slot in slots {
+        let i = res.items.len();
+        match slot {
+            Slot::Ours(o) => {
+                res.ours[o] = Some(i);
+                res.items.push(ours[o].clone());
+            }
+            Slot::Theirs(t) => {
+                if let Some(&o) = ours_added.iter().find(|&&o| ours[o] == theirs[t]) {
+                    duplicates.push((t, o));
+                } else {
+                    res.theirs[t] = Some(i);
+                    res.items.push(theirs[t].clone());
+                }
+            }
+            Slot::Base(b, Some(o), Some(t)) => {
+                let item = if ours[o] == base[b] || ours[o] == theirs[t] {
+                    theirs[t].clone()
+                } else if theirs[t] == base[b] {
+                    ours[o].clone()
+                } else {
+                    merge(&base[b], &ours[o], &theirs[t], i)
+                };
+                res.base[b] = Some(i);
+                res.ours[o] = Some(i);
+                res.theirs[t] = Some(i);
+                res.items.push(item);
+            }
+            Slot::Base(b, Some(o), None) => {
+                if ours[o] != base[b] {
+                    res.deleted_edited.push((i, Side::Theirs));
+                } else if keep_deleted {
+                    res.deleted.push(i);
+                } else {
+                    continue;
+                }
+                res.base[b] = Some(i);
+                res.ours[o] = Some(i);
+                res.items.push(ours[o].clone());
+            }
+            Slot::Base(b, None, Some(t)) => {
+                if theirs[t] != base[b] {
+                    res.deleted_edited.push((i, Side::Ours));
+                } else if keep_deleted {
+                    res.deleted.push(i);
+                } else {
+                    continue;
+                }
+                res.base[b] = Some(i);
+                res.theirs[t] = Some(i);
+                res.items.push(theirs[t].clone());
+            }
+            Slot::Base(_, None, None) => (),
+        }
+    }
+
+    for (t, o) in duplicates {
+        res.theirs[t] = res.ours[o];
+    }
+
+    res
+}
+
+fn to_u16(i: usize) -> u16 {
+    i as u16
+}
+
+fn merge_info(
+    base: &twmap::Info,
+    ours: &twmap::Info,
+    theirs: &twmap::Info,
+    conflicts: &mut Vec<Conflict>,
+) -> twmap::Info {
+    let mut info = ours.clone();
+
+    macro_rules! merge_field {
+        ($($field:ident),*) => {{
+            $(
+                if ours.$field == base.$field {
+                    info.$field = theirs.$field.clone();
+                } else if theirs.$field != base.$field && theirs.$field != ours.$field {
+                    conflicts.push(Conflict {
+                        item: ConflictItem::Info {
+                            field: stringify!($field).to_owned(),
+                        },
+                        kind: ConflictKind::Edited,
+                    });
+                }
+            )*
+        }};
+    }
+
+    merge_field!(author, version, credits, license, settings);
+    info
+}
+
+fn merge_tiles<T: AnyTile>(
+    base: &Array2<T>,
+    ours: &Array2<T>,
+    theirs: &Array2<T>,
+) -> (Array2<T>, Vec<Rect<u32, u32>>) {
+    if ours == base || ours == theirs {
+        (theirs.clone(), Vec::new())
+    } else if theirs == base {
+        (ours.clone(), Vec::new())
+    } else if ours.dim() == base.dim() && theirs.dim() == base.dim() {
+        let mut tiles = ours.clone();
+        let mut conflicts = Array2::from_elem(base.dim(), false);
+
+        for (pos, tile) in tiles.indexed_iter_mut() {
+            let (b, o, t) = (base[pos], ours[pos], theirs[pos]);
+            if o == b {
+                *tile = t;
+            } else if t != b && t != o {
+                conflicts[pos] = true;
+            }
+        }
+
+        (tiles, changed_rects(&conflicts))
+    } else {
+        // one side resized the layer, the other edited it
+        let (h, w) = ours.dim();
+        (ours.clone(), vec![Rect::new(0, 0, w as u32, h as u32)])
+    }
+}
+
+fn copy_layer_properties(tgt: &mut Layer, src: &Layer) {
+    match (tgt, src) {
+        (Layer::Tiles(tgt), Layer::Tiles(src)) => {
+            tgt.name = src.name.clone();
+            tgt.detail = src.detail;
+            tgt.color = src.color;
+            tgt.color_env = src.color_env;
+            tgt.color_env_offset = src.color_env_offset;
+            tgt.image = src.image;
+            tgt.automapper_config = src.automapper_config.clone();
+        }
+        (Layer::Quads(tgt), Layer::Quads(src)) => {
+            tgt.name = src.name.clone();
+            tgt.detail = src.detail;
+            tgt.image = src.image;
+        }
+        (Layer::Sounds(tgt), Layer::Sounds(src)) => {
+            tgt.name = src.name.clone();
+            tgt.detail = src.detail;
+            tgt.sound = src.sound;
+        }
+        _ => (),
+    }
+}
+
+fn merge_layer(
+    base: &Layer,
+    ours: &Layer,
+    theirs: &Layer,
+    group: u16,
+    layer: u16,
+    conflicts: &mut Vec<Conflict>,
+) -> Layer {
+    let mut res = ours.clone();
+
+    if layer_properties_eq(ours, base) {
+        copy_layer_properties(&mut res, theirs);
+    } else if !layer_properties_eq(theirs, base) && !layer_properties_eq(theirs, ours) {
+        conflicts.push(Conflict {
+            item: ConflictItem::Layer { group, layer },
+            kind: ConflictKind::Edited,
+        });
+    }
+
+    macro_rules! merge_tilemap {
+        ($res:ident, $base:ident, $ours:ident, $theirs:ident) => {{
+            let (tiles, rects) = merge_tiles(
+                $base.tiles.unwrap_ref(),
+                $ours.tiles.unwrap_ref(),
+                $theirs.tiles.unwrap_ref(),
+            );
+            *$res.tiles.unwrap_mut() = tiles;
+            if !rects.is_empty() {
+                conflicts.push(Conflict {
+                    item: ConflictItem::Layer { group, layer },
+                    kind: ConflictKind::Tiles { rects },
+                });
+            }
+        }};
+    }
+
+    match (&mut res, base, ours, theirs) {
+        (Layer::Game(r), Layer::Game(b), Layer::Game(o), Layer::Game(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Tiles(r), Layer::Tiles(b), Layer::Tiles(o), Layer::Tiles(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Front(r), Layer::Front(b), Layer::Front(o), Layer::Front(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Tele(r), Layer::Tele(b), Layer::Tele(o), Layer::Tele(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Speedup(r), Layer::Speedup(b), Layer::Speedup(o), Layer::Speedup(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Switch(r), Layer::Switch(b), Layer::Switch(o), Layer::Switch(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Tune(r), Layer::Tune(b), Layer::Tune(o), Layer::Tune(t)) => {
+            merge_tilemap!(r, b, o, t)
+        }
+        (Layer::Quads(r), Layer::Quads(b), Layer::Quads(o), Layer::Quads(t)) => {
+            let mut edited = Vec::new();
+            let quads = merge_items(
+                &b.quads,
+                &o.quads,
+                &t.quads,
+                |a, b| a == b,
+                |_, _| true,
+                false,
+                |_, ours, _, i| {
+                    edited.push(i);
+                    ours.clone()
+                },
+            );
+            let deleted = quads.deleted_edited.into_iter();
+            conflicts.extend(
+                edited
+                    .into_iter()
+                    .map(|i| (i, ConflictKind::Edited))
+                    .chain(
+                        deleted
+                            .map(|(i, deleted_by)| (i, ConflictKind::DeletedEdited { deleted_by })),
+                    )
+                    .map(|(i, kind)| Conflict {
+                        item: ConflictItem::Quad {
+                            group,
+                            layer,
+                            quad: to_u16(i),
+                        },
+                        kind,
+                    }),
+            );
+            r.quads = quads.items;
+        }
+        (Layer::Sounds(r), Layer::Sounds(b), Layer::Sounds(o), Layer::Sounds(t)) => {
+            if o.sources == b.sources {
+                r.sources = t.sources.clone();
+            } else if t.sources != b.sources && t.sources != o.sources {
+                conflicts.push(Conflict {
+                    item: ConflictItem::Layer { group, layer },
+                    kind: ConflictKind::Edited,
+                });
+            }
+        }
+        _ => (),
+    }
+
+    res
+}
+
+fn merge_group(
+    base: &twmap::Group,
+    ours: &twmap::Group,
+    theirs: &twmap::Group,
+    group: u16,
+    conflicts: &mut Vec<Conflict>,
+) -> twmap::Group {
+    let mut res = if group_properties_eq(ours, base) {
+        theirs.clone()
+    } else {
+        if !group_properties_eq(theirs, base) && !group_properties_eq(theirs, ours) {
+            conflicts.push(Conflict {
+                item: ConflictItem::Group { group },
+                kind: ConflictKind::Edited,
+            });
+        }
+        ours.clone()
+    };
+
+    let layers = merge_items(
+        &base.layers,
+        &ours.layers,
+        &theirs.layers,
+        same_layer,
+        |a, b| a.kind() == b.kind(),
+        false,
+        |b, o, t, i| merge_layer(b, o, t, group, to_u16(i), conflicts),
+    );
+
+    conflicts.extend(
+        layers
+            .deleted_edited
+            .into_iter()
+            .map(|(i, deleted_by)| Conflict {
+                item: ConflictItem::Layer {
+                    group,
+                    layer: to_u16(i),
+                },
+                kind: ConflictKind::DeletedEdited { deleted_by },
+            }),
+    );
+
+    res.layers = layers.items;
+    res
+}
+
+// Converts the image, envelope and sound indices of the layers to those of the merged map.
+fn remap_groups(
+    map: &TwMap,
+    images: &[Option<usize>],
+    envelopes: &[Option<usize>],
+    sounds: &[Option<usize>],
+) -> Vec<twmap::Group> {
+    let mut map = TwMap {
+        groups: map.groups.clone(),
+        ..TwMap::empty(map.version)
+    };
+
+    fn remap(tbl: &[Option<usize>]) -> impl Fn(Option<u16>) -> Option<u16> + '_ {
+        |i| i.and_then(|i| tbl.get(i as usize).copied().flatten().map(to_u16))
+    }
+
+    map.edit_image_indices(remap(images));
+    map.edit_env_indices(remap(envelopes));
+    map.edit_sound_indices(remap(sounds));
+    map.groups
+}
+
+/// Merges the changes from `base` to `ours` and from `base` to `theirs`. All maps must be loaded.
+pub fn merge_maps(base: &TwMap, ours: &TwMap, theirs: &TwMap) -> (TwMap, Vec<Conflict>) {
+    let mut conflicts = Vec::new();
+
+    let info = merge_info(&base.info, &ours.info, &theirs.info, &mut conflicts);
+
+    macro_rules! merge_resource {
+        ($list:ident, $same:expr, $compatible:expr, $item:ident { $field:ident }) => {{
+            let mut edited = Vec::new();
+            let merged = merge_items(
+                &base.$list,
+                &ours.$list,
+                &theirs.$list,
+                $same,
+                $compatible,
+                true,
+                |_, ours, _, i| {
+                    edited.push(i);
+                    ours.clone()
+                },
+            );
+            let deleted = merged.deleted_edited.iter();
+            conflicts.extend(
+                edited
+                    .into_iter()
+                    .map(|i| (i, ConflictKind::Edited))
+                    .chain(
+                        deleted.map(|&(i, deleted_by)| {
+                            (i, ConflictKind::DeletedEdited { deleted_by })
+                        }),
+                    )
+                    .map(|(i, kind)| Conflict {
+                        item: ConflictItem::$item { $field: to_u16(i) },
+                        kind,
+                    }),
+            );
+            merged
+        }};
+    }
+
+    let images = merge_resource!(images, same_image, |_, _| true, Image { image });
+    let envelopes = merge_resource!(
+        envelopes,
+        same_envelope,
+        same_envelope_type,
+        Envelope { envelope }
+    );
+    let sounds = merge_resource!(
+        sounds,
+        |a: &twmap::Sound, b: &twmap::Sound| a.name == b.name,
+        |_, _| true,
+        Sound { sound }
+    );
+
+    let base_groups = remap_groups(base, &images.base, &envelopes.base, &sounds.base);
+    let ours_groups = remap_groups(ours, &images.ours, &envelopes.ours, &sounds.ours);
+    let theirs_groups = remap_groups(theirs, &images.theirs, &envelopes.theirs, &sounds.theirs);
+
+    let groups = merge_items(
+        &base_groups,
+        &ours_groups,
+        &theirs_groups,
+        same_group,
+        |_, _| true,
+        false,
+        |b, o, t, i| merge_group(b, o, t, to_u16(i), &mut conflicts),
+    );
+
+    conflicts.extend(
+        groups
+            .deleted_edited
+            .into_iter()
+            .map(|(i, deleted_by)| Conflict {
+                item: ConflictItem::Group { group: to_u16(i) },
+                kind: ConflictKind::DeletedEdited { deleted_by },
+            }),
+    );
+
+    let mut map = TwMap {
+        version: if ours.version == base.version {
+            theirs.version
+        } else {
+            ours.version
+        },
+        info,
+        images: images.items,
+        envelopes: envelopes.items,
+        groups: groups.items,
+        sounds: sounds.items,
+    };
+
+    // resources deleted on one side are removed unless the merged layers still use them
+    macro_rules! remove_deleted {
+        ($list:ident, $deleted:expr, $in_use:ident, $edit_indices:ident, $item:ident { $field:ident }) => {
+            for &i in $deleted.iter().rev() {
+                let i = to_u16(i);
+                if map.$in_use(i) {
+                    continue;
+                }
+                map.$list.remove(i as usize);
+                map.$edit_indices(|j| j.map(|j| if j > i { j - 1 } else { j }));
+                for c in &mut conflicts {
+                    if let ConflictItem::$item { $field } = &mut c.item {
+                        if *$field > i {
+                            *$field -= 1;
+                        }
+                    }
+                }
+            }
+        };
+    }
+
+    remove_deleted!(
+        images,
+        images.deleted,
+        is_image_in_use,
+        edit_image_indices,
+        Image { image }
+    );
+    remove_deleted!(
+        envelopes,
+        envelopes.deleted,
+        is_env_in_use,
+        edit_env_indices,
+        Envelope { envelope }
+    );
+    remove_deleted!(
+        sounds,
+        sounds.deleted,
+        is_sound_in_use,
+        edit_sound_indices,
+        Sound { sound }
+    );
+
+    (map, conflicts)
+}
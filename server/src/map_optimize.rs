@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use twmap::{Image, LayerKind, TwMap};
+
+use crate::error::Error;
+
+// Removes what does not contribute to the map, like the "Remove unused" tools
+// of the ddnet editor, and reports what was removed.
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OptimizeReport {
+    /// (group name, layer name) of the removed empty layers.
+    pub layers: Vec<(String, String)>,
+    pub groups: Vec<String>,
+    /// Embedded images identical to a previous image, which is used instead.
+    pub duplicate_images: Vec<String>,
+    pub images: Vec<String>,
+    pub envelopes: Vec<String>,
+    /// Size of the map file before and after, in bytes.
+    pub size_before: usize,
+    pub size_after: usize,
+}
+
+impl OptimizeReport {
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+            && self.groups.is_empty()
+            && self.duplicate_images.is_empty()
+            && self.images.is_empty()
+            && self.envelopes.is_empty()
+    }
+}
+
+// saving changes the map, the size is measured on a copy.
+fn file_size(map: &TwMap) -> Result<usize, Error> {
+    let mut buf = Vec::new();
+    map.clone()
+        .save(&mut buf)
+        .map_err(|e| Error::Map(e.to_string()))?;
+    Ok(buf.len())
+}
+
+// indices above index are shifted down by one.
+fn removed_index(index: u16) -> impl Fn(Option<u16>) -> Option<u16> {
+    move |i| i.map(|i| if i > index { i - 1 } else { i })
+}
+
+fn remove_empty_layers(map: &mut TwMap, report: &mut OptimizeReport) {
+    for group in &mut map.groups {
+        group.layers.retain(|layer| {
+            // physics and sounds layers are kept, the clients cannot create them again.
+            let remove =
+                matches!(layer.kind(), LayerKind::Tiles | LayerKind::Quads) && layer.is_empty();
+            if remove {
+                report
+                    .layers
+                    .push((group.name.clone(), layer.name().to_owned()));
+            }
+            !remove
+        });
+    }
+}
+
+fn remove_empty_groups(map: &mut TwMap, report: &mut OptimizeReport) {
+    map.groups.retain(|group| {
+        if group.layers.is_empty() {
+            report.groups.push(group.name.clone());
+        }
+        !group.layers.is_empty()
+    });
+}
+
+fn merge_duplicate_images(map: &mut TwMap, report: &mut OptimizeReport) {
+    let mut i = 1;
+    while i < map.images.len() {
+        let original = match &map.images[i] {
+            Image::Embedded(image) => map.images[..i].iter().position(|other| match other {
+                Image::Embedded(other) => other.image.unwrap_ref() == image.image.unwrap_ref(),
+                Image::External(_) => false,
+            }),
+            Image::External(_) => None,
+        };
+
+        if let Some(original) = original {
+            let image = map.images.remove(i);
+            report.duplicate_images.push(image.name().to_owned());
+            let (i, original) = (i as u16, original as u16);
+            map.edit_image_indices(|index| {
+                index.map(|index| if index == i { original } else { index })
+            });
+            map.edit_image_indices(removed_index(i));
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn remove_unused_images(map: &mut TwMap, report: &mut OptimizeReport) {
+    let mut i = 0;
+    while i < map.images.len() {
+        if map.is_image_in_use(i as u16) {
+            i += 1;
+        } else {
+            let image = map.images.remove(i);
+            report.images.push(image.name().to_owned());
+            map.edit_image_indices(removed_index(i as u16));
+        }
+    }
+}
+
+fn remove_unused_envelopes(map: &mut TwMap, report: &mut OptimizeReport) {
+    let mut i = 0;
+    while i < map.envelopes.len() {
+        if map.is_env_in_use(i as u16) {
+            i += 1;
+        } else {
+            let env = map.envelopes.remove(i);
+            report.envelopes.push(env.name().to_owned());
+            map.edit_env_indices(removed_index(i as u16));
+        }
+    }
+}
+
+pub fn optimize_map(map: &mut TwMap) -> Result<OptimizeReport, Error> {
+    let mut report = OptimizeReport {
+        size_before: file_size(map)?,
+        ..Default::default()
+    };
+
+    // layers first: removing them can leave groups, images and envelopes unused.
+    remove_empty_layers(map, &mut report);
+    remove_empty_groups(map, &mut report);
+    merge_duplicate_images(map, &mut report);
+    remove_unused_images(map, &mut report);
+    remove_unused_envelopes(map, &mut report);
+
+    report.size_after = file_size(map)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use twmap::{
+        CompressedData, FrontLayer, GameLayer, Group, Layer, QuadsLayer, SoundsLayer, TilesLayer,
+        TwMap, Version,
+    };
+
+    use super::*;
+
+    #[test]
+    fn remove_empty_layers_keeps_physics_and_sounds_layers() {
+        let mut map = TwMap::empty(Version::DDNet06);
+        let mut physics = Group::physics();
+        physics.layers.push(Layer::Game(GameLayer {
+            tiles: CompressedData::Loaded(ndarray::Array2::default((2, 2))),
+        }));
+        physics.layers.push(Layer::Front(FrontLayer {
+            tiles: CompressedData::Loaded(ndarray::Array2::default((2, 2))),
+        }));
+        map.groups.push(physics);
+
+        let mut group = Group {
+            name: "Background".to_owned(),
+            ..Group::default()
+        };
+        group.layers.push(Layer::Tiles(TilesLayer::new((2, 2))));
+        group.layers.push(Layer::Quads(QuadsLayer::default()));
+        group.layers.push(Layer::Sounds(SoundsLayer::default()));
+        map.groups.push(group);
+
+        let mut report = OptimizeReport::default();
+        remove_empty_layers(&mut map, &mut report);
+
+        assert_eq!(report.layers.len(), 2);
+        let kinds: Vec<_> = map
+            .groups
+            .iter()
+            .flat_map(|group| group.layers.iter().map(|layer| layer.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [LayerKind::Game, LayerKind::Front, LayerKind::Sounds]
+        );
+    }
+}
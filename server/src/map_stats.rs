@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use twmap::{compression::compress, AnyTile, CurveKind, Envelope, Image, Layer, Quad, TwMap};
+
+use crate::{error::Error, util::ViewAsBytes};
+
+// Breakdown of the size of a map file, to find out what makes a map too big.
+// Embedded images, tiles, quads and sounds are zlib-compressed data items in the
+// map file, their size is the size of the data once compressed. Envelope points
+// are stored uncompressed. Item headers and names are negligible and not counted.
+
+// size of a CEnvPoint and a CEnvPointBezier in the map file.
+const ENV_POINT_SIZE: usize = 6 * 4;
+const ENV_POINT_BEZIER_SIZE: usize = 16 * 4;
+// size of a CSoundSource in the map file.
+const SOUND_SOURCE_SIZE: usize = 13 * 4;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemStats {
+    pub name: String,
+    pub size: usize,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayerStats {
+    pub name: String,
+    pub kind: String,
+    pub size: usize,
+    /// Number of tiles of each id, empty tiles excluded.
+    pub tiles: Option<BTreeMap<u8, usize>>,
+    pub quads: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupStats {
+    pub name: String,
+    pub size: usize,
+    pub layers: Vec<LayerStats>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SizeTotals {
+    pub images: usize,
+    pub envelopes: usize,
+    pub sounds: usize,
+    pub layers: usize,
+    /// Size of the saved map file.
+    pub file: usize,
+    /// The size limit of map files on this server.
+    pub max: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapStats {
+    pub images: Vec<ItemStats>,
+    pub envelopes: Vec<ItemStats>,
+    pub sounds: Vec<ItemStats>,
+    pub groups: Vec<GroupStats>,
+    pub totals: SizeTotals,
+}
+
+fn image_size(image: &Image) -> usize {
+    match image {
+        Image::External(_) => 0,
+        Image::Embedded(image) => compress(image.image.unwrap_ref().as_raw()).len(),
+    }
+}
+
+fn env_size(env: &Envelope) -> usize {
+    fn points_size<T: Copy>(env: &twmap::Env<T>) -> usize {
+        let bezier = env
+            .points
+            .iter()
+            .any(|point| matches!(point.curve, CurveKind::Bezier(_)));
+        let point_size = if bezier {
+            ENV_POINT_SIZE + ENV_POINT_BEZIER_SIZE
+        } else {
+            ENV_POINT_SIZE
+        };
+        env.points.len() * point_size
+    }
+
+    match env {
+        Envelope::Position(env) => points_size(env),
+        Envelope::Color(env) => points_size(env),
+        Envelope::Sound(env) => points_size(env),
+    }
+}
+
+// a CQuad in the map file.
+fn quad_bytes(quad: &Quad) -> Vec<u8> {
+    let points = quad
+        .corners
+        .iter()
+        .chain([&quad.position])
+        .flat_map(|p| [p.x.to_bits(), p.y.to_bits()]);
+    let colors = quad
+        .colors
+        .iter()
+        .flat_map(|c| [c.r, c.g, c.b, c.a].map(i32::from));
+    let uvs = quad
+        .texture_coords
+        .iter()
+        .flat_map(|uv| [uv.u.to_bits(), uv.v.to_bits()]);
+    let env = |env: Option<u16>| env.map(i32::from).unwrap_or(-1);
+    let envs = [
+        env(quad.position_env),
+        quad.position_env_offset,
+        env(quad.color_env),
+        quad.color_env_offset,
+    ];
+
+    points
+        .chain(colors)
+        .chain(uvs)
+        .chain(envs)
+        .flat_map(i32::to_le_bytes)
+        .collect()
+}
+
+fn tiles_stats<T: AnyTile>(tiles: &ndarray::Array2<T>) -> (usize, BTreeMap<u8, usize>) {
+    let data = tiles.to_owned().into_raw_vec().into_boxed_slice();
+    let size = compress(&ViewAsBytes::into_boxed_bytes(data)).len();
+
+    let mut counts = BTreeMap::new();
+    for tile in tiles.iter().filter(|tile| tile.id() != 0) {
+        *counts.entry(tile.id()).or_default() += 1;
+    }
+
+    (size, counts)
+}
+
+fn layer_stats(layer: &Layer) -> LayerStats {
+    let mut stats = LayerStats {
+        name: layer.name().to_owned(),
+        kind: format!("{:?}", layer.kind()).to_lowercase(),
+        size: 0,
+        tiles: None,
+        quads: None,
+    };
+
+    macro_rules! tiles {
+        ($layer: ident) => {{
+            let (size, counts) = tiles_stats($layer.tiles.unwrap_ref());
+            stats.size = size;
+            stats.tiles = Some(counts);
+        }};
+    }
+
+    match layer {
+        Layer::Game(layer) => tiles!(layer),
+        Layer::Tiles(layer) => tiles!(layer),
+        Layer::Front(layer) => tiles!(layer),
+        Layer::Tele(layer) => tiles!(layer),
+        Layer::Speedup(layer) => tiles!(layer),
+        Layer::Switch(layer) => tiles!(layer),
+        Layer::Tune(layer) => tiles!(layer),
+        Layer::Quads(layer) => {
+            let data: Vec<u8> = layer.quads.iter().flat_map(quad_bytes).collect();
+            stats.size = compress(&data).len();
+            stats.quads = Some(layer.quads.len());
+        }
+        Layer::Sounds(layer) => stats.size = layer.sources.len() * SOUND_SOURCE_SIZE,
+        Layer::Invalid(_) => (),
+    }
+
+    stats
+}
+
+pub fn map_stats(map: &mut TwMap, max_size: usize) -> Result<MapStats, Error> {
+    let images: Vec<_> = map
+        .images
+        .iter()
+        .map(|image| ItemStats {
+            name: image.name().to_owned(),
+            size: image_size(image),
+        })
+        .collect();
+
+    let envelopes: Vec<_> = map
+        .envelopes
+        .iter()
+        .map(|env| ItemStats {
+            name: env.name().to_owned(),
+            size: env_size(env),
+        })
+        .collect();
+
+    let sounds: Vec<_> = map
+        .sounds
+        .iter()
+        .map(|sound| ItemStats {
+            name: sound.name.clone(),
+            size: compress(sound.data.unwrap_ref()).len(),
+        })
+        .collect();
+
+    let groups: Vec<_> = map
+        .groups
+        .iter()
+        .map(|group| {
+            let layers: Vec<_> = group.layers.iter().map(layer_stats).collect();
+            GroupStats {
+                name: group.name.clone(),
+                size: layers.iter().map(|l| l.size).sum(),
+                layers,
+            }
+        })
+        .collect();
+
+    let mut buf = Vec::new();
+    map.save(&mut buf).map_err(|e| Error::Map(e.to_string()))?;
+
+    let totals = SizeTotals {
+        images: images.iter().map(|i| i.size).sum(),
+        envelopes: envelopes.iter().map(|e| e.size).sum(),
+        sounds: sounds.iter().map(|s| s.size).sum(),
+        layers: groups.iter().map(|g| g.size).sum(),
+        file: buf.len(),
+        max: max_size,
+    };
+
+    Ok(MapStats {
+        images,
+        envelopes,
+        sounds,
+        groups,
+        totals,
+    })
+}
@@ -0,0 +1,263 @@
+use ndarray::{s, ArrayView2};
+use twmap::{Env, Envelope, Group, Layer, Quad, TwMap};
+
+use crate::{
+    base64::Base64,
+    error::Error,
+    map_copy::CopyReport,
+    protocol::{
+        CopyReq, CreateReq, DeleteReq, EditReq, Image, MoveReq, PartialEnv, PartialEnvelope,
+        PartialGroup, PartialLayer, PartialQuadsLayer, PartialTilesLayer, Request, Tiles,
+    },
+    quads_edit::QuadsOp,
+    render::encode_png,
+    util::ViewAsBytes,
+};
+
+// The clients apply the basic requests (creating, editing, moving or deleting one
+// item) to their copy of the map. The requests which change the map in other ways
+// are sent to the peers as the basic requests that recreate their result.
+
+pub fn tiles_request<T: ViewAsBytes + Clone>(
+    group_index: u16,
+    layer_index: u16,
+    x: u32,
+    y: u32,
+    tiles: ArrayView2<T>,
+) -> Request {
+    let (h, w) = tiles.dim();
+    let data = tiles.to_owned().into_raw_vec().into_boxed_slice();
+    let tiles = Tiles {
+        rect: vek::Rect::new(x, y, w as u32, h as u32),
+        tiles: Base64(ViewAsBytes::into_boxed_bytes(data).into_vec()),
+    };
+    Request::Edit(EditReq::Tiles(group_index, layer_index, Box::new(tiles)))
+}
+
+pub fn create_image_request(image: &twmap::Image) -> Result<Request, Error> {
+    let create = match image {
+        twmap::Image::External(image) => Image::External {
+            size: image.size,
+            embed: false,
+        },
+        twmap::Image::Embedded(image) => {
+            Image::Embedded(Base64(encode_png(image.image.unwrap_ref())?))
+        }
+    };
+    Ok(Request::Create(CreateReq::Image(
+        image.name().to_owned(),
+        create,
+    )))
+}
+
+/// Request setting the tiles of a region of a tilemap layer.
+pub fn layer_tiles_request(
+    group_index: u16,
+    layer_index: u16,
+    layer: &Layer,
+    rect: vek::Rect<u32, u32>,
+) -> Option<Request> {
+    let (x, y, w, h) = (
+        rect.x as usize,
+        rect.y as usize,
+        rect.w as usize,
+        rect.h as usize,
+    );
+    macro_rules! request {
+        ($layer:expr) => {{
+            let tiles = $layer.tiles.unwrap_ref().slice(s![y..y + h, x..x + w]);
+            Some(tiles_request(
+                group_index,
+                layer_index,
+                rect.x,
+                rect.y,
+                tiles,
+            ))
+        }};
+    }
+    match layer {
+        Layer::Game(l) => request!(l),
+        Layer::Tiles(l) => request!(l),
+        Layer::Front(l) => request!(l),
+        Layer::Tele(l) => request!(l),
+        Layer::Speedup(l) => request!(l),
+        Layer::Switch(l) => request!(l),
+        Layer::Tune(l) => request!(l),
+        Layer::Quads(_) | Layer::Sounds(_) | Layer::Invalid(_) => None,
+    }
+}
+
+fn env_part<T: Copy>(env: &Env<T>) -> PartialEnv<T> {
+    PartialEnv {
+        name: Some(env.name.clone()),
+        synchronized: Some(env.synchronized),
+        points: Some(env.points.clone()),
+    }
+}
+
+pub fn create_envelope_request(env: &Envelope) -> Request {
+    let part = match env {
+        Envelope::Position(env) => PartialEnvelope::Position(env_part(env)),
+        Envelope::Color(env) => PartialEnvelope::Color(env_part(env)),
+        Envelope::Sound(env) => PartialEnvelope::Sound(env_part(env)),
+    };
+    Request::Create(CreateReq::Envelope(Box::new(part)))
+}
+
+fn group_part(group: &Group) -> PartialGroup {
+    PartialGroup {
+        name: Some(group.name.clone()),
+        offset: Some(group.offset),
+        parallax: Some(group.parallax),
+        clipping: Some(group.clipping),
+        clip: Some(group.clip),
+    }
+}
+
+/// Only tiles and quads layers can be created by the clients, the other layers cannot
+/// be sent to the peers.
+pub fn check_creatable(layer: &Layer) -> Result<(), Error> {
+    match layer {
+        Layer::Tiles(_) | Layer::Quads(_) => Ok(()),
+        Layer::Sounds(_) => Err(Error::CopySoundsLayer),
+        _ => Err(Error::WrongLayerType),
+    }
+}
+
+/// Requests creating the layer at the end of the group, at `layer_index`. The layer
+/// must pass `check_creatable`.
+pub fn create_layer_requests(group_index: u16, layer_index: u16, layer: &Layer) -> Vec<Request> {
+    match layer {
+        Layer::Tiles(layer) => {
+            let tiles = layer.tiles.unwrap_ref();
+            let (h, w) = tiles.dim();
+            let part = PartialTilesLayer {
+                width: Some(w),
+                height: Some(h),
+                name: Some(layer.name.clone()),
+                detail: Some(layer.detail),
+                color: Some(layer.color),
+                color_env: Some(layer.color_env),
+                color_env_offset: Some(layer.color_env_offset),
+                image: Some(layer.image),
+                automapper_config: Some(layer.automapper_config.clone()),
+            };
+            vec![
+                Request::Create(CreateReq::Layer(
+                    group_index,
+                    Box::new(PartialLayer::Tiles(part)),
+                )),
+                tiles_request(group_index, layer_index, 0, 0, tiles.view()),
+            ]
+        }
+        Layer::Quads(layer) => {
+            let part = PartialQuadsLayer {
+                name: Some(layer.name.clone()),
+                detail: Some(layer.detail),
+                image: Some(layer.image),
+            };
+            let create = Request::Create(CreateReq::Layer(
+                group_index,
+                Box::new(PartialLayer::Quads(part)),
+            ));
+            let mut reqs = vec![create];
+            reqs.extend(create_quads_requests(
+                group_index,
+                layer_index,
+                &layer.quads,
+            ));
+            reqs
+        }
+        _ => vec![],
+    }
+}
+
+/// Requests creating the group at the end of the map, at `group_index`, with its layers.
+/// The layers must pass `check_creatable`.
+pub fn create_group_requests(group_index: u16, group: &Group) -> Vec<Request> {
+    let mut reqs = vec![Request::Create(CreateReq::Group(Box::new(group_part(
+        group,
+    ))))];
+    for (i, layer) in group.layers.iter().enumerate() {
+        reqs.extend(create_layer_requests(group_index, i as u16, layer));
+    }
+    reqs
+}
+
+/// Requests adding the quads on top of the layer.
+pub fn create_quads_requests(group_index: u16, layer_index: u16, quads: &[Quad]) -> Vec<Request> {
+    quads
+        .iter()
+        .map(|quad| {
+            Request::Create(CreateReq::Quad(
+                group_index,
+                layer_index,
+                Box::new(quad.clone()),
+            ))
+        })
+        .collect()
+}
+
+/// Requests applying an edit of many quads, from the quads of the layer after the edit.
+pub fn edit_quads_requests(
+    group_index: u16,
+    layer_index: u16,
+    quads: &[Quad],
+    indices: &[u16],
+    op: &QuadsOp,
+) -> Vec<Request> {
+    let mut indices = indices.to_vec();
+    indices.sort_unstable();
+    indices.dedup();
+
+    if let QuadsOp::Delete = op {
+        // from the last one, so that the indices of the others stay valid.
+        indices
+            .iter()
+            .rev()
+            .map(|&q| Request::Delete(DeleteReq::Quad(group_index, layer_index, q)))
+            .collect()
+    } else {
+        indices
+            .iter()
+            .map(|&q| {
+                let quad = Box::new(quads[q as usize].clone());
+                Request::Edit(EditReq::Quad(group_index, layer_index, q, quad))
+            })
+            .collect()
+    }
+}
+
+/// Requests adding what a copy added to the map.
+pub fn copy_requests(
+    map: &TwMap,
+    req: &CopyReq,
+    report: &CopyReport,
+) -> Result<Vec<Request>, Error> {
+    let mut reqs = Vec::new();
+    for &i in &report.images {
+        reqs.push(create_image_request(&map.images[i as usize])?);
+    }
+    for &i in &report.envelopes {
+        reqs.push(create_envelope_request(&map.envelopes[i as usize]));
+    }
+    match (req, &report.index[..]) {
+        (CopyReq::Group(..), &[g]) => {
+            reqs.extend(create_group_requests(g, &map.groups[g as usize]));
+        }
+        (CopyReq::Layer(..), &[g, l]) => {
+            let layer = &map.groups[g as usize].layers[l as usize];
+            reqs.extend(create_layer_requests(g, l, layer));
+        }
+        _ => (),
+    }
+    Ok(reqs)
+}
+
+pub fn move_group_request(src: u16, tgt: u16) -> Option<Request> {
+    (src != tgt).then_some(Request::Move(MoveReq::Group(src, tgt)))
+}
+
+pub fn move_layer_request(src: (u16, u16), tgt: (u16, u16)) -> Option<Request> {
+    (src != tgt).then_some(Request::Move(MoveReq::Layer(src, tgt)))
+}
@@ -0,0 +1,323 @@
+use fixed::types::{I17F15, I27F5};
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+use twmap::{edit::TileFlips, Layer, TwMap};
+use vek::{Rect, Vec2};
+
+use crate::error::Error;
+
+// Mirroring, rotation and shifting of a map or of a region of a group. The flags of
+// the tiles (and the angle of speedups) are updated so that the tiles look transformed too.
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+    /// Left <-> right.
+    FlipX,
+    /// Up <-> down.
+    FlipY,
+    /// 90° clockwise.
+    RotateCw,
+    /// 90° counterclockwise.
+    RotateCcw,
+    /// Moves by (x, y) tiles.
+    Shift { x: i32, y: i32 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Region {
+    pub group: u16,
+    /// In tiles. Only square regions can be rotated.
+    #[serde(flatten)]
+    pub rect: Rect<u32, u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapTransform {
+    pub transform: Transform,
+    /// Only transform the layers of a group in this region, instead of the whole map.
+    #[serde(default)]
+    pub region: Option<Region>,
+}
+
+impl Transform {
+    // new position in a region of size (w, h) of a tile at (x, y).
+    fn tile_pos(self, (x, y): (usize, usize), (w, h): (usize, usize)) -> (isize, isize) {
+        let (x, y, w, h) = (x as isize, y as isize, w as isize, h as isize);
+        match self {
+            Transform::FlipX => (w - 1 - x, y),
+            Transform::FlipY => (x, h - 1 - y),
+            Transform::RotateCw => (h - 1 - y, x),
+            Transform::RotateCcw => (y, w - 1 - x),
+            Transform::Shift { x: dx, y: dy } => (x + dx as isize, y + dy as isize),
+        }
+    }
+
+    // new position in a region of size (w, h) of a point at (x, y).
+    fn point_pos(self, p: Vec2<I17F15>, (w, h): (I17F15, I17F15)) -> Option<Vec2<I17F15>> {
+        Some(match self {
+            Transform::FlipX => Vec2::new(w.checked_sub(p.x)?, p.y),
+            Transform::FlipY => Vec2::new(p.x, h.checked_sub(p.y)?),
+            Transform::RotateCw => Vec2::new(h.checked_sub(p.y)?, p.x),
+            Transform::RotateCcw => Vec2::new(p.y, w.checked_sub(p.x)?),
+            Transform::Shift { x, y } => Vec2::new(
+                p.x.checked_add(I17F15::checked_from_num(x)?)?,
+                p.y.checked_add(I17F15::checked_from_num(y)?)?,
+            ),
+        })
+    }
+
+    fn flip_tile<T: TileFlips>(self, tile: &mut T) {
+        match self {
+            Transform::FlipX => tile.flip_x(),
+            Transform::FlipY => tile.flip_y(),
+            Transform::RotateCw => tile.rotate_cw(),
+            Transform::RotateCcw => tile.rotate_ccw(),
+            Transform::Shift { .. } => (),
+        }
+    }
+
+    fn is_rotation(self) -> bool {
+        matches!(self, Transform::RotateCw | Transform::RotateCcw)
+    }
+
+    fn offset(self) -> (i64, i64) {
+        match self {
+            Transform::Shift { x, y } => (x as i64, y as i64),
+            _ => (0, 0),
+        }
+    }
+}
+
+fn transform_tiles<T: TileFlips>(tiles: &mut Array2<T>, rect: Rect<usize, usize>, t: Transform) {
+    let (x, y, w, h) = (rect.x, rect.y, rect.w, rect.h);
+    let region = tiles.slice(s![y..y + h, x..x + w]).to_owned();
+    tiles.slice_mut(s![y..y + h, x..x + w]).fill(T::default());
+    let (height, width) = tiles.dim();
+
+    for ((ty, tx), tile) in region.indexed_iter() {
+        let mut tile = *tile;
+        t.flip_tile(&mut tile);
+        let (nx, ny) = t.tile_pos((tx, ty), (w, h));
+        // shifted tiles can leave the layer.
+        let (nx, ny) = (x as isize + nx, y as isize + ny);
+        if (0..width as isize).contains(&nx) && (0..height as isize).contains(&ny) {
+            tiles[(ny as usize, nx as usize)] = tile;
+        }
+    }
+}
+
+fn transform_layer(layer: &mut Layer, rect: Rect<usize, usize>, t: Transform) {
+    match layer {
+        Layer::Game(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Tiles(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Front(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Tele(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Speedup(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Switch(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Tune(l) => transform_tiles(l.tiles.unwrap_mut(), rect, t),
+        Layer::Quads(_) | Layer::Sounds(_) | Layer::Invalid(_) => (),
+    }
+}
+
+fn transform_region(map: &mut TwMap, region: &Region, t: Transform) -> Result<(), Error> {
+    let rect = region.rect;
+    if rect.w == 0 || rect.h == 0 {
+        return Err(Error::Invalid("region"));
+    }
+    // a rotated region must stay in place.
+    if t.is_rotation() && rect.w != rect.h {
+        return Err(Error::Invalid("rotation of a non-square region"));
+    }
+    let src = Rect::new(rect.x as i64, rect.y as i64, rect.w as i64, rect.h as i64);
+    // the region once transformed.
+    let (dx, dy) = t.offset();
+    let dst = Rect::new(src.x + dx, src.y + dy, src.w, src.h);
+
+    let group = map
+        .groups
+        .get_mut(region.group as usize)
+        .ok_or(Error::GroupNotFound)?;
+
+    // check all the layers before changing any.
+    let fits = |r: Rect<i64, i64>, (w, h): (i64, i64)| {
+        r.x >= 0 && r.y >= 0 && r.x + r.w <= w && r.y + r.h <= h
+    };
+    for layer in &group.layers {
+        if let Some(shape) = layer.shape() {
+            let size = (shape.w as i64, shape.h as i64);
+            if !fits(src, size) || !fits(dst, size) {
+                return Err(Error::TilesOutOfBounds);
+            }
+        }
+    }
+
+    let tiles_rect = Rect::new(
+        rect.x as usize,
+        rect.y as usize,
+        rect.w as usize,
+        rect.h as usize,
+    );
+    let fix = |v: i64| I17F15::checked_from_num(v).ok_or(Error::TilesOutOfBounds);
+    let (x, y, w, h) = (fix(src.x)?, fix(src.y)?, fix(src.w)?, fix(src.h)?);
+    let origin = Vec2::new(x, y);
+    let (x_end, y_end) = (fix(src.x + src.w)?, fix(src.y + src.h)?);
+    let in_region = |p: Vec2<I17F15>| p.x >= x && p.x < x_end && p.y >= y && p.y < y_end;
+    let point = |p: Vec2<I17F15>| {
+        t.point_pos(p - origin, (w, h))
+            .and_then(|p| Some(Vec2::new(p.x.checked_add(x)?, p.y.checked_add(y)?)))
+            .ok_or(Error::Invalid("quad position"))
+    };
+
+    // quads and sources are transformed on copies, in case a position overflows.
+    let mut moved = Vec::new();
+    for (i, layer) in group.layers.iter().enumerate() {
+        match layer {
+            Layer::Quads(layer) => {
+                let mut layer = layer.clone();
+                for quad in layer.quads.iter_mut().filter(|q| in_region(q.position)) {
+                    quad.position = point(quad.position)?;
+                    for corner in &mut quad.corners {
+                        *corner = point(*corner)?;
+                    }
+                }
+                moved.push((i, Layer::Quads(layer)));
+            }
+            Layer::Sounds(layer) => {
+                let mut layer = layer.clone();
+                for source in &mut layer.sources {
+                    if in_region(source.area.position()) {
+                        source.area.set_position(point(source.area.position())?);
+                    }
+                }
+                moved.push((i, Layer::Sounds(layer)));
+            }
+            _ => (),
+        }
+    }
+
+    for (i, layer) in moved {
+        group.layers[i] = layer;
+    }
+
+    for layer in &mut group.layers {
+        transform_layer(layer, tiles_rect, t);
+    }
+
+    Ok(())
+}
+
+// the whole map, every layer and group clip being moved by the same offset. Tiles
+// moved out of their layer are lost.
+fn shift_whole(mut map: TwMap, t: Transform) -> Result<TwMap, Error> {
+    let zero = (I17F15::ZERO, I17F15::ZERO);
+    let point = |p| t.point_pos(p, zero).ok_or(Error::Invalid("quad position"));
+    let (dx, dy) = t.offset();
+    let clip_pos = |pos: I27F5, d: i64| {
+        I27F5::checked_from_num(d)
+            .and_then(|d| pos.checked_add(d))
+            .ok_or(Error::Invalid("group clip"))
+    };
+
+    for group in map.groups.iter_mut().filter(|g| g.clipping) {
+        group.clip.x = clip_pos(group.clip.x, dx)?;
+        group.clip.y = clip_pos(group.clip.y, dy)?;
+    }
+
+    for layer in map.groups.iter_mut().flat_map(|g| g.layers.iter_mut()) {
+        match layer {
+            Layer::Quads(layer) => {
+                for quad in &mut layer.quads {
+                    quad.position = point(quad.position)?;
+                    for corner in &mut quad.corners {
+                        *corner = point(*corner)?;
+                    }
+                }
+            }
+            Layer::Sounds(layer) => {
+                for source in &mut layer.sources {
+                    source.area.set_position(point(source.area.position())?);
+                }
+            }
+            layer => {
+                if let Some(shape) = layer.shape() {
+                    transform_layer(layer, Rect::new(0, 0, shape.w, shape.h), t);
+                }
+            }
+        }
+    }
+
+    Ok(map)
+}
+
+// the whole map, with the transforms of twmap which also handle the group
+// offsets and clips. Note that they move non-physics layers out of the physics group.
+fn transform_whole(map: &TwMap, t: Transform) -> Result<TwMap, Error> {
+    let rotate = |map: TwMap, n: usize| (0..n).try_fold(map, |map, _| map.rotate_right());
+
+    let map = map.clone();
+    let map = match t {
+        Transform::FlipX => map.mirror(),
+        Transform::FlipY => rotate(map, 2).and_then(TwMap::mirror),
+        Transform::RotateCw => map.rotate_right(),
+        Transform::RotateCcw => rotate(map, 3),
+        Transform::Shift { .. } => return shift_whole(map, t),
+    };
+
+    map.ok_or(Error::Invalid("map transform overflows"))
+}
+
+pub fn transform_map(map: &mut TwMap, transform: &MapTransform) -> Result<(), Error> {
+    match &transform.region {
+        Some(region) => transform_region(map, region, transform.transform),
+        None => {
+            *map = transform_whole(map, transform.transform)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twmap::{CompressedData, GameLayer, Group, Version};
+
+    use super::*;
+
+    #[test]
+    fn shift_whole_moves_group_clips() {
+        let mut map = TwMap::empty(Version::DDNet06);
+        let mut physics = Group::physics();
+        physics.layers.push(Layer::Game(GameLayer {
+            tiles: CompressedData::Loaded(Array2::default((4, 4))),
+        }));
+        map.groups.push(physics);
+        let clip = Rect::new(
+            I27F5::from_num(1),
+            I27F5::from_num(2),
+            I27F5::from_num(3),
+            I27F5::from_num(4),
+        );
+        map.groups.push(Group {
+            clipping: true,
+            clip,
+            ..Group::default()
+        });
+        map.groups.push(Group {
+            clipping: false,
+            clip,
+            ..Group::default()
+        });
+
+        let map = shift_whole(map, Transform::Shift { x: 2, y: -1 }).unwrap();
+
+        let shifted = Rect::new(
+            I27F5::from_num(3),
+            I27F5::from_num(1),
+            I27F5::from_num(3),
+            I27F5::from_num(4),
+        );
+        assert_eq!(map.groups[0].clip, Group::physics().clip);
+        assert_eq!(map.groups[1].clip, shifted);
+        assert_eq!(map.groups[2].clip, clip);
+    }
+}
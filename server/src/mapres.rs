@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use image::RgbaImage;
+
+use crate::{error::Error, util::check_file_name};
+
+// External images are not stored in the maps, the game reads them from its mapres
+// directory. When --data dirs are given, their mapres directories are used the same way.
+pub struct Mapres {
+    dirs: Vec<PathBuf>,
+    // decoded images by name. Mapres are not expected to change.
+    cache: Mutex<HashMap<String, Arc<RgbaImage>>>,
+}
+
+impl Mapres {
+    pub fn new(data_dirs: &[PathBuf]) -> Self {
+        let dirs = data_dirs
+            .iter()
+            .map(|dir| dir.join("mapres"))
+            .filter(|dir| dir.is_dir())
+            .collect();
+
+        Mapres {
+            dirs,
+            cache: Default::default(),
+        }
+    }
+
+    fn read(&self, name: &str) -> Option<RgbaImage> {
+        let file_name = format!("{name}.png");
+        self.dirs.iter().find_map(|dir| {
+            let path = dir.join(&file_name);
+            if !path.is_file() {
+                return None;
+            }
+            match image::open(&path) {
+                Ok(img) => Some(img.into_rgba8()),
+                Err(e) => {
+                    log::warn!("failed to read mapres `{}`: {e}", path.display());
+                    None
+                }
+            }
+        })
+    }
+
+    /// The pixels of the external image `name`, if it exists in a mapres directory.
+    pub fn get(&self, name: &str) -> Option<Arc<RgbaImage>> {
+        if !check_file_name(name) {
+            return None;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(image) = cache.get(name) {
+            return Some(image.clone());
+        }
+
+        let image = Arc::new(self.read(name)?);
+        cache.insert(name.to_owned(), image.clone());
+        Some(image)
+    }
+
+    /// The pixels of the external images of the map, None for embedded or missing images.
+    pub fn external_images(&self, map: &twmap::TwMap) -> Vec<Option<Arc<RgbaImage>>> {
+        map.images
+            .iter()
+            .map(|image| match image {
+                twmap::Image::External(image) => self.get(&image.name),
+                twmap::Image::Embedded(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn embed(&self, image: &twmap::ExternalImage) -> Result<twmap::EmbeddedImage, Error> {
+        let data = self
+            .get(&image.name)
+            .ok_or(Error::NotFound("external image data"))?;
+
+        Ok(twmap::EmbeddedImage {
+            name: image.name.clone(),
+            image: (*data).clone().into(),
+        })
+    }
+}
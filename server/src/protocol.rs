@@ -9,7 +9,23 @@ use serde_with::{
 use twmap::{AutomapperConfig, EnvPoint, Position, Volume};
 use vek::{Extent2, Rect, Rgba, Uv, Vec2};
 
-use crate::{base64::Base64, error::Error, map_cfg::MapAccess};
+use crate::{
+    automap::{AutomapDiff, AutomapPreview, AutomapRegion},
+    base64::Base64,
+    envelope::{EnvSample, EnvelopeEval, EnvelopeUsage},
+    error::Error,
+    library::{ImportedAutomapper, LibraryAutomapper},
+    map_cfg::MapAccess,
+    map_copy::CopyReport,
+    map_diff::MapDiff,
+    map_optimize::OptimizeReport,
+    map_stats::MapStats,
+    map_transform::MapTransform,
+    quads_edit::QuadsOp,
+    tiles_find::{TilesFound, TilesQuery, TilesReplacement},
+    tiles_remap::{RemapReport, RemapTiles},
+    uploads::UploadStatus,
+};
 
 // Some documentation about the communication between clients and the server:
 // ----------
@@ -42,6 +58,8 @@ pub struct MapConfig {
 pub struct MapDetail {
     pub name: String,
     pub users: usize,
+    /// Path of the PNG thumbnail of the map on the HTTP server.
+    pub thumbnail: String,
 }
 
 // AUTOMAPPERS
@@ -64,7 +82,7 @@ pub struct AutomapperDetail {
     pub configs: Option<Vec<String>>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Span {
     pub line_start: u32,
     pub col_start: u32,
@@ -72,10 +90,20 @@ pub struct Span {
     pub col_end: u32,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Error,
+    Warning,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AutomapperDiagnostic {
     pub span: Span,
     pub msg: String,
+    #[serde(default)]
+    pub severity: Severity,
 }
 
 // TILES
@@ -136,6 +164,8 @@ pub struct PartialConfig {
 #[serde(rename_all = "snake_case")]
 pub enum CreationMethod {
     Upload(Base64),
+    // the id of a complete chunked upload
+    UploadId(String),
     Clone(String),
     Blank { w: u32, h: u32 },
 }
@@ -292,10 +322,27 @@ pub struct PartialAutomapper {}
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Image {
-    External { size: Extent2<u32> },
+    External {
+        size: Extent2<u32>,
+        // embed the image from the server mapres instead
+        #[serde(default)]
+        embed: bool,
+    },
+    // the id of a complete chunked upload containing the png
+    Upload {
+        upload_id: String,
+    },
     Embedded(Base64),
 }
 
+#[skip_serializing_none]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PartialImage {
+    pub name: Option<String>,
+    pub data: Option<Image>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HttpReq {
     pub method: String,
@@ -320,6 +367,10 @@ pub enum GetReq {
     Envelopes,
     #[serde(rename = "get/envelope")]
     Envelope(u16),
+    #[serde(rename = "get/envelope_eval")]
+    EnvelopeEval(u16, Box<EnvelopeEval>),
+    #[serde(rename = "get/envelope_usage")]
+    EnvelopeUsage,
     #[serde(rename = "get/groups")]
     Groups,
     #[serde(rename = "get/group")]
@@ -332,10 +383,18 @@ pub enum GetReq {
     Tiles(u16, u16),
     #[serde(rename = "get/quad")]
     Quad(u16, u16, u16),
+    #[serde(rename = "find/tiles")]
+    FindTiles(Box<TilesQuery>),
     #[serde(rename = "get/automappers")]
     Automappers,
     #[serde(rename = "get/automapper")]
     Automapper(String),
+    #[serde(rename = "get/automap_preview")]
+    AutomapPreview(u16, u16, Box<AutomapPreview>),
+    #[serde(rename = "get/diff")]
+    Diff(Option<Base64>),
+    #[serde(rename = "get/stats")]
+    Stats,
 }
 
 #[serde_as]
@@ -383,8 +442,26 @@ pub enum EditReq {
         u16,
         #[serde_as(as = "Box<SerdeQuad>")] Box<twmap::Quad>,
     ),
+    #[serde(rename = "edit/quads")]
+    Quads(u16, u16, Vec<u16>, Box<QuadsOp>),
+    #[serde(rename = "replace/tiles")]
+    ReplaceTiles(Box<TilesQuery>, Box<TilesReplacement>),
+    #[serde(rename = "edit/remap_tiles")]
+    RemapTiles(u16, u16, Box<RemapTiles>),
     #[serde(rename = "edit/automap")]
     Automap(u16, u16),
+    #[serde(rename = "edit/automap_region")]
+    AutomapRegion(u16, u16, Box<AutomapRegion>),
+    #[serde(rename = "edit/image")]
+    Image(u16, Box<PartialImage>),
+    #[serde(rename = "edit/embed_image")]
+    EmbedImage(u16),
+    #[serde(rename = "edit/unembed_image")]
+    UnembedImage(u16),
+    #[serde(rename = "edit/optimize")]
+    Optimize,
+    #[serde(rename = "edit/transform")]
+    Transform(Box<MapTransform>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -394,6 +471,8 @@ pub enum DeleteReq {
     Image(u16),
     #[serde(rename = "delete/envelope")]
     Envelope(u16),
+    #[serde(rename = "delete/envelope_force")]
+    ForceEnvelope(u16),
     #[serde(rename = "delete/group")]
     Group(u16),
     #[serde(rename = "delete/layer")]
@@ -404,6 +483,58 @@ pub enum DeleteReq {
     Automapper(String),
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum DuplicateReq {
+    #[serde(rename = "duplicate/group")]
+    Group(u16),
+    #[serde(rename = "duplicate/layer")]
+    Layer(u16, u16),
+    #[serde(rename = "duplicate/quads")]
+    Quads(u16, u16, Vec<u16>),
+}
+
+/// Copies from another map (the first field), with the images, envelopes and sounds used.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum CopyReq {
+    #[serde(rename = "copy/group")]
+    Group(String, u16),
+    /// The layer is added to the group of the last field.
+    #[serde(rename = "copy/layer")]
+    Layer(String, (u16, u16), u16),
+    #[serde(rename = "copy/image")]
+    Image(String, u16),
+    #[serde(rename = "copy/envelope")]
+    Envelope(String, u16),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum LibraryReq {
+    #[serde(rename = "library/list")]
+    List,
+    /// Copies a version of an automapper of the library to the map, the latest if not given.
+    #[serde(rename = "library/import")]
+    Import(String, Option<u32>),
+    /// Adds an automapper of the map to the library as a new version.
+    #[serde(rename = "library/publish")]
+    Publish(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum UploadReq {
+    #[serde(rename = "upload/start")]
+    Start(usize),
+    #[serde(rename = "upload/status")]
+    Status(String),
+    #[serde(rename = "upload/chunk")]
+    Chunk(String, usize, Base64),
+    #[serde(rename = "upload/cancel")]
+    Cancel(String),
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "content")]
 pub enum MoveReq {
@@ -448,6 +579,14 @@ pub enum Request {
     Delete(DeleteReq),
     #[serde(untagged)]
     Move(MoveReq),
+    #[serde(untagged)]
+    Duplicate(DuplicateReq),
+    #[serde(untagged)]
+    Copy(CopyReq),
+    #[serde(untagged)]
+    Upload(UploadReq),
+    #[serde(untagged)]
+    Library(LibraryReq),
 }
 
 #[serde_as]
@@ -461,17 +600,35 @@ pub enum Response {
     Image(Base64),
     Envelopes(Vec<String>),
     Envelope(Box<twmap::Envelope>),
+    EnvelopeSamples(Vec<EnvSample>),
+    EnvelopeUsage(Vec<EnvelopeUsage>),
     Groups(Vec<String>),
     Group(Box<twmap::Group>),
     Layers(Vec<String>),
     Layer(Box<twmap::Layer>),
     Tiles(Base64),
     Quad(#[serde_as(as = "Box<SerdeQuad>")] Box<twmap::Quad>),
+    FoundTiles(Box<TilesFound>),
+    /// Number of replaced tiles.
+    Replaced(usize),
+    Remapped(Box<RemapReport>),
+    Automapped(Box<AutomapDiff>),
     Automappers(Vec<AutomapperDetail>),
     AutomapperDiagnostics(Vec<AutomapperDiagnostic>),
     Automapper(String),
+    Library(Vec<LibraryAutomapper>),
+    /// Version of an automapper of the library.
+    Version(u32),
+    Imported(Box<ImportedAutomapper>),
     Users(usize),
     Cursors(HashMap<String, Cursor>),
+    Diff(Box<MapDiff>),
+    Optimized(Box<OptimizeReport>),
+    /// Index of the duplicated group, (group, layer) index of the duplicated layer or indices of the duplicated quads.
+    Duplicated(Vec<u16>),
+    Copied(Box<CopyReport>),
+    Stats(Box<MapStats>),
+    Upload(UploadStatus),
 }
 
 // Messages that are sent unrequested from the client.
@@ -482,6 +639,9 @@ pub enum Broadcast {
     MapDeleted(String),
     Users(usize),
     Saved,
+    /// The map changed too much to be synchronized with requests, the peers must
+    /// download it again.
+    Reload,
 }
 
 #[serde_as]
@@ -0,0 +1,160 @@
+use fixed::types::I17F15;
+use serde::{Deserialize, Serialize};
+use serde_with::rust::double_option;
+use twmap::Quad;
+use vek::{Rgba, Vec2};
+
+use crate::error::Error;
+
+// Operations on many quads of a layer at once. Positions are in tiles, like the
+// corners and position of the quads.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum QuadsOp {
+    Translate {
+        offset: Vec2<I17F15>,
+    },
+    /// Rotates clockwise by `angle` degrees, around `center` or the position of each quad.
+    Rotate {
+        angle: f64,
+        #[serde(default)]
+        center: Option<Vec2<I17F15>>,
+    },
+    /// Scales by `factor` (negative to mirror), from `center` or the position of each quad.
+    Scale {
+        factor: Vec2<f64>,
+        #[serde(default)]
+        center: Option<Vec2<I17F15>>,
+    },
+    /// Sets the color of the 4 corners.
+    Color {
+        color: Rgba<u8>,
+    },
+    /// Sets the given envelopes and offsets, the others are unchanged.
+    Envelopes {
+        #[serde(
+            default,
+            with = "double_option",
+            skip_serializing_if = "Option::is_none"
+        )]
+        position_env: Option<Option<u16>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        position_env_offset: Option<i32>,
+        #[serde(
+            default,
+            with = "double_option",
+            skip_serializing_if = "Option::is_none"
+        )]
+        color_env: Option<Option<u16>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color_env_offset: Option<i32>,
+    },
+    /// Rounds the corners to the nearest tile.
+    Snap,
+    Delete,
+}
+
+fn to_f64(p: Vec2<I17F15>) -> Vec2<f64> {
+    p.map(|c| c.to_num())
+}
+
+fn to_fixed(p: Vec2<f64>) -> Result<Vec2<I17F15>, Error> {
+    let c = |c: f64| I17F15::checked_from_num(c).ok_or(Error::Invalid("quad position"));
+    Ok(Vec2::new(c(p.x)?, c(p.y)?))
+}
+
+// applies f to the corners and the position of the quad, relative to the center.
+fn transform(
+    quad: &mut Quad,
+    center: Option<Vec2<I17F15>>,
+    f: impl Fn(Vec2<f64>) -> Vec2<f64>,
+) -> Result<(), Error> {
+    let center = to_f64(center.unwrap_or(quad.position));
+    for point in quad.corners.iter_mut().chain([&mut quad.position]) {
+        *point = to_fixed(center + f(to_f64(*point) - center))?;
+    }
+    Ok(())
+}
+
+fn apply(quad: &mut Quad, op: &QuadsOp) -> Result<(), Error> {
+    match op {
+        QuadsOp::Translate { offset } => {
+            let offset = to_f64(*offset);
+            transform(quad, None, |p| p + offset)?;
+        }
+        QuadsOp::Rotate { angle, center } => {
+            let (sin, cos) = angle.to_radians().sin_cos();
+            transform(quad, *center, |p| {
+                Vec2::new(p.x * cos - p.y * sin, p.x * sin + p.y * cos)
+            })?;
+        }
+        QuadsOp::Scale { factor, center } => {
+            transform(quad, *center, |p| p * *factor)?;
+        }
+        QuadsOp::Color { color } => quad.colors = [*color; 4],
+        QuadsOp::Envelopes {
+            position_env,
+            position_env_offset,
+            color_env,
+            color_env_offset,
+        } => {
+            if let Some(env) = position_env {
+                quad.position_env = *env;
+            }
+            if let Some(offset) = position_env_offset {
+                quad.position_env_offset = *offset;
+            }
+            if let Some(env) = color_env {
+                quad.color_env = *env;
+            }
+            if let Some(offset) = color_env_offset {
+                quad.color_env_offset = *offset;
+            }
+        }
+        QuadsOp::Snap => {
+            for corner in &mut quad.corners {
+                *corner = to_fixed(to_f64(*corner).round())?;
+            }
+        }
+        QuadsOp::Delete => (),
+    }
+    Ok(())
+}
+
+/// Applies the operation to the quads at the given indices. On error, the quads are unchanged.
+pub fn edit_quads(quads: &mut Vec<Quad>, indices: &[u16], op: &QuadsOp) -> Result<(), Error> {
+    let mut indices: Vec<usize> = indices.iter().map(|&i| i as usize).collect();
+    indices.sort_unstable();
+    indices.dedup();
+
+    if indices.last().is_some_and(|&i| i >= quads.len()) {
+        return Err(Error::QuadNotFound);
+    }
+
+    if let QuadsOp::Delete = op {
+        let mut indices = indices.iter().peekable();
+        let mut i = 0;
+        quads.retain(|_| {
+            let delete = indices.next_if_eq(&&i).is_some();
+            i += 1;
+            !delete
+        });
+        return Ok(());
+    }
+
+    let edited = indices
+        .iter()
+        .map(|&i| {
+            let mut quad = quads[i].clone();
+            apply(&mut quad, op)?;
+            Ok(quad)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    for (i, quad) in indices.into_iter().zip(edited) {
+        quads[i] = quad;
+    }
+
+    Ok(())
+}
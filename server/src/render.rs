@@ -0,0 +1,356 @@
+use std::sync::Arc;
+
+use image::RgbaImage;
+use serde::Deserialize;
+use twmap::{GameLayer, Layer, Quad, QuadsLayer, TileFlags, TilesLayer, TwMap};
+use vek::{Rect, Rgba, Vec2};
+
+use crate::error::Error;
+
+// CPU renderer for tiles and quads layers, used for map exports and thumbnails.
+// Groups are rendered as seen in-game with the camera at the center of the
+// rendered region. Envelopes and physics layers are not rendered.
+// External images are rendered if found in the mapres of the --data dirs.
+
+/// Maximum number of pixels of a rendered image.
+pub const MAX_RENDER_PIXELS: u64 = 4096 * 4096;
+
+/// Size of the map thumbnails, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RenderParams {
+    /// Size of a tile in pixels. Default: 32.
+    pub zoom: Option<f32>,
+    /// Rendered region, in tiles. Default: the game layer.
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub w: Option<f32>,
+    pub h: Option<f32>,
+}
+
+/// Area of the map covered by the game layer, in tiles.
+pub fn map_bounds(map: &TwMap) -> Rect<f32, f32> {
+    let shape = map
+        .find_physics_layer::<GameLayer>()
+        .map(|l| l.tiles.shape())
+        .unwrap_or_default();
+    Rect::new(0.0, 0.0, shape.w as f32, shape.h as f32)
+}
+
+pub struct Viewport {
+    /// Rendered region, in tiles.
+    pub region: Rect<f32, f32>,
+    /// Size of a tile in pixels.
+    pub zoom: f32,
+}
+
+impl Viewport {
+    pub fn new(map: &TwMap, params: &RenderParams) -> Result<Self, Error> {
+        let bounds = map_bounds(map);
+        let region = Rect::new(
+            params.x.unwrap_or(bounds.x),
+            params.y.unwrap_or(bounds.y),
+            params.w.unwrap_or(bounds.w),
+            params.h.unwrap_or(bounds.h),
+        );
+        let zoom = params.zoom.unwrap_or(32.0);
+
+        let viewport = Self { region, zoom };
+        let (w, h) = viewport.size();
+
+        if !(zoom > 0.0 && region.w > 0.0 && region.h > 0.0)
+            || region.x.is_nan()
+            || region.y.is_nan()
+        {
+            Err(Error::Invalid("render region"))
+        } else if w as u64 * h as u64 > MAX_RENDER_PIXELS {
+            Err(Error::RenderTooBig)
+        } else {
+            Ok(viewport)
+        }
+    }
+
+    /// Fits the whole map in a box of `size`x`size` pixels.
+    pub fn fit(map: &TwMap, size: u32) -> Self {
+        let region = map_bounds(map);
+        let zoom = (size as f32 / region.w.max(region.h)).min(64.0);
+        Self { region, zoom }
+    }
+
+    /// Size of the rendered image in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        let w = (self.region.w * self.zoom)
+            .round()
+            .clamp(1.0, u32::MAX as f32);
+        let h = (self.region.h * self.zoom)
+            .round()
+            .clamp(1.0, u32::MAX as f32);
+        (w as u32, h as u32)
+    }
+
+    // pixel area covered by a rectangle in tiles, clamped to the image.
+    fn pixel_rect(&self, rect: Rect<f32, f32>) -> (u32, u32, u32, u32) {
+        let (w, h) = self.size();
+        let to_px = |v: f32, min: f32, max: u32| ((v - min) * self.zoom).clamp(0.0, max as f32);
+        (
+            to_px(rect.x, self.region.x, w).floor() as u32,
+            to_px(rect.y, self.region.y, h).floor() as u32,
+            to_px(rect.x + rect.w, self.region.x, w).ceil() as u32,
+            to_px(rect.y + rect.h, self.region.y, h).ceil() as u32,
+        )
+    }
+
+    // center of a pixel, in tiles.
+    fn to_tiles(&self, x: u32, y: u32) -> Vec2<f32> {
+        Vec2::new(
+            self.region.x + (x as f32 + 0.5) / self.zoom,
+            self.region.y + (y as f32 + 0.5) / self.zoom,
+        )
+    }
+}
+
+fn blend(dst: &mut image::Rgba<u8>, src: Rgba<f32>) {
+    let [r, g, b, a] = dst.0.map(|c| c as f32 / 255.0);
+    let out_a = src.a + a * (1.0 - src.a);
+    if out_a <= 0.0 {
+        return;
+    }
+    let mix = |s: f32, d: f32| (s * src.a + d * a * (1.0 - src.a)) / out_a;
+    let out = [mix(src.r, r), mix(src.g, g), mix(src.b, b), out_a];
+    dst.0 = out.map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8);
+}
+
+fn texel(image: &RgbaImage, x: u32, y: u32) -> Rgba<f32> {
+    let px = image.get_pixel(x.min(image.width() - 1), y.min(image.height() - 1));
+    Rgba::from(px.0).map(|c: u8| c as f32 / 255.0)
+}
+
+fn to_color(color: Rgba<u8>) -> Rgba<f32> {
+    color.map(|c| c as f32 / 255.0)
+}
+
+// texture coordinates in the tile of a point (u, v) of the rendered tile.
+fn tile_uv(u: f32, v: f32, flags: TileFlags) -> (f32, f32) {
+    // rotation happens after flipping, so it is undone first.
+    let (mut u, mut v) = if flags.contains(TileFlags::ROTATE) {
+        (v, 1.0 - u)
+    } else {
+        (u, v)
+    };
+    if flags.contains(TileFlags::FLIP_X) {
+        u = 1.0 - u;
+    }
+    if flags.contains(TileFlags::FLIP_Y) {
+        v = 1.0 - v;
+    }
+    (u, v)
+}
+
+fn render_tiles(
+    canvas: &mut RgbaImage,
+    viewport: &Viewport,
+    clip: Rect<f32, f32>,
+    origin: Vec2<f32>,
+    layer: &TilesLayer,
+    image: Option<&RgbaImage>,
+) {
+    let tiles = layer.tiles.unwrap_ref();
+    let (rows, cols) = tiles.dim();
+    let color = to_color(layer.color);
+
+    let bounds = Rect::new(origin.x, origin.y, cols as f32, rows as f32).intersection(clip);
+    let (x0, y0, x1, y1) = viewport.pixel_rect(bounds);
+    let tile_size = image.map_or(1, |img| (img.width() / 16).max(1));
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let pos = viewport.to_tiles(x, y) - origin;
+            let (col, row) = (pos.x.floor(), pos.y.floor());
+            if col < 0.0 || row < 0.0 || col >= cols as f32 || row >= rows as f32 {
+                continue;
+            }
+            let tile = tiles[(row as usize, col as usize)];
+            if tile.id == 0 {
+                continue;
+            }
+
+            let src = match image {
+                Some(image) => {
+                    let (u, v) = tile_uv(pos.x - col, pos.y - row, tile.flags);
+                    let tx = (tile.id % 16) as u32 * tile_size + (u * tile_size as f32) as u32;
+                    let ty = (tile.id / 16) as u32 * tile_size + (v * tile_size as f32) as u32;
+                    texel(image, tx, ty)
+                }
+                None => Rgba::one(),
+            };
+
+            blend(canvas.get_pixel_mut(x, y), src * color);
+        }
+    }
+}
+
+fn cross(a: Vec2<f32>, b: Vec2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+fn render_triangle(
+    canvas: &mut RgbaImage,
+    viewport: &Viewport,
+    clip: Rect<f32, f32>,
+    points: [(Vec2<f32>, Rgba<f32>, Vec2<f32>); 3],
+    image: Option<&RgbaImage>,
+) {
+    let [(p0, c0, t0), (p1, c1, t1), (p2, c2, t2)] = points;
+
+    let area = cross(p1 - p0, p2 - p0);
+    if area.abs() <= f32::EPSILON {
+        return;
+    }
+
+    let min = Vec2::<f32>::partial_min(Vec2::partial_min(p0, p1), p2);
+    let max = Vec2::<f32>::partial_max(Vec2::partial_max(p0, p1), p2);
+    let bounds = Rect::new(min.x, min.y, max.x - min.x, max.y - min.y).intersection(clip);
+    let (x0, y0, x1, y1) = viewport.pixel_rect(bounds);
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let p = viewport.to_tiles(x, y);
+            if !clip.contains_point(p) {
+                continue;
+            }
+            let w0 = cross(p2 - p1, p - p1) / area;
+            let w1 = cross(p0 - p2, p - p2) / area;
+            let w2 = 1.0 - w0 - w1;
+            if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                continue;
+            }
+
+            let color = c0 * w0 + c1 * w1 + c2 * w2;
+            let src = match image {
+                Some(image) => {
+                    // textures wrap around
+                    let uv = t0 * w0 + t1 * w1 + t2 * w2;
+                    let tx = (uv.x.rem_euclid(1.0) * image.width() as f32) as u32;
+                    let ty = (uv.y.rem_euclid(1.0) * image.height() as f32) as u32;
+                    texel(image, tx, ty)
+                }
+                None => Rgba::one(),
+            };
+
+            blend(canvas.get_pixel_mut(x, y), src * color);
+        }
+    }
+}
+
+fn render_quad(
+    canvas: &mut RgbaImage,
+    viewport: &Viewport,
+    clip: Rect<f32, f32>,
+    origin: Vec2<f32>,
+    quad: &Quad,
+    image: Option<&RgbaImage>,
+) {
+    let point = |i: usize| {
+        let pos = quad.corners[i].map(|c| c.to_num::<f32>()) + origin;
+        let uv = quad.texture_coords[i];
+        let uv = Vec2::new(uv.u.to_num::<f32>(), uv.v.to_num::<f32>());
+        (pos, to_color(quad.colors[i]), uv)
+    };
+
+    // corners are top-left, top-right, bottom-left, bottom-right
+    let [tl, tr, bl, br] = [0, 1, 2, 3].map(point);
+    render_triangle(canvas, viewport, clip, [tl, tr, bl], image);
+    render_triangle(canvas, viewport, clip, [tr, br, bl], image);
+}
+
+fn render_quads(
+    canvas: &mut RgbaImage,
+    viewport: &Viewport,
+    clip: Rect<f32, f32>,
+    origin: Vec2<f32>,
+    layer: &QuadsLayer,
+    image: Option<&RgbaImage>,
+) {
+    for quad in &layer.quads {
+        render_quad(canvas, viewport, clip, origin, quad, image);
+    }
+}
+
+pub fn encode_png(image: &RgbaImage) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    image
+        .write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
+        .map_err(|e| Error::Internal(e.to_string().into()))?;
+    Ok(buf)
+}
+
+/// Decoded image of each image of the map. `external` are the pixels of the external
+/// images (see `Mapres::external_images`).
+pub fn map_images<'a>(
+    map: &'a TwMap,
+    external: &'a [Option<Arc<RgbaImage>>],
+) -> Vec<Option<&'a RgbaImage>> {
+    map.images
+        .iter()
+        .zip(external)
+        .map(|(image, external)| match image {
+            twmap::Image::External(_) => external.as_deref(),
+            twmap::Image::Embedded(image) => Some(image.image.unwrap_ref()),
+        })
+        .collect()
+}
+
+/// Renders the tiles and quads layers of a loaded map. `images` are the decoded images
+/// of the map, `None` for images whose data is not available (their layers are skipped).
+pub fn render_map(map: &TwMap, images: &[Option<&RgbaImage>], viewport: &Viewport) -> RgbaImage {
+    let (w, h) = viewport.size();
+    let mut canvas = RgbaImage::new(w, h);
+    let center = viewport.region.center();
+
+    for group in &map.groups {
+        let parallax = group.parallax.map(|p| p as f32 / 100.0);
+        let offset = group.offset.map(|o| o.to_num::<f32>());
+        // position of the group origin when the camera is at the center of the region
+        let origin = center * (Vec2::one() - parallax) - offset;
+
+        let clip = if group.clipping {
+            let clip = group.clip;
+            Rect::new(
+                clip.x.to_num::<f32>(),
+                clip.y.to_num::<f32>(),
+                clip.w.to_num::<f32>(),
+                clip.h.to_num::<f32>(),
+            )
+        } else {
+            viewport.region
+        };
+
+        for layer in &group.layers {
+            let image = match layer {
+                Layer::Tiles(TilesLayer { image, .. }) | Layer::Quads(QuadsLayer { image, .. }) => {
+                    match image {
+                        Some(i) => match images.get(*i as usize) {
+                            Some(Some(image)) => Some(*image),
+                            _ => continue,
+                        },
+                        None => None,
+                    }
+                }
+                _ => continue,
+            };
+
+            match layer {
+                Layer::Tiles(layer) => {
+                    render_tiles(&mut canvas, viewport, clip, origin, layer, image)
+                }
+                Layer::Quads(layer) => {
+                    render_quads(&mut canvas, viewport, clip, origin, layer, image)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    canvas
+}
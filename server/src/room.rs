@@ -24,7 +24,7 @@ fn server_error<E: std::fmt::Display>(err: E) -> Error {
     Error::Internal("".into())
 }
 
-fn load_map(path: &Path) -> Result<twmap::TwMap, twmap::Error> {
+pub(crate) fn load_map(path: &Path) -> Result<twmap::TwMap, twmap::Error> {
     let mut map = twmap::TwMap::parse(&std::fs::read(path)?)?;
     map.load()?;
     Ok(map)
@@ -97,6 +97,7 @@ pub struct Room {
     peers: Mutex<HashMap<SocketAddr, RoomPeer>>,
     map: LazyMap,
     saving: Mutex<()>, // this mutex prevents multiple users from saving at the same time
+    thumbnail: Mutex<Option<Vec<u8>>>, // png of the saved map, rendered on demand
 }
 
 const MAP_FILE_NAME: &str = "map.map";
@@ -136,6 +137,7 @@ impl Room {
             peers: Mutex::new(HashMap::new()),
             map,
             saving: Mutex::new(()),
+            thumbnail: Mutex::new(None),
         })
     }
 
@@ -171,6 +173,7 @@ impl Room {
             peers: Mutex::new(HashMap::new()),
             map,
             saving: Mutex::new(()),
+            thumbnail: Mutex::new(None),
         })
     }
 
@@ -235,6 +238,10 @@ impl Room {
         self.peers.lock()
     }
 
+    pub fn thumbnail(&self) -> MutexGuard<'_, Option<Vec<u8>>> {
+        self.thumbnail.lock()
+    }
+
     pub fn remove_closed_peers(&self) {
         let mut peers = self.peers();
         peers.retain(|_, p| !p.tx.is_closed());
@@ -276,6 +283,8 @@ impl Room {
             Ok(())
         })()?;
 
+        *self.thumbnail() = None;
+
         log::debug!("map saved `{}`", self.map.path.display());
         Ok(())
     }
@@ -2,7 +2,7 @@ use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
     body::Bytes,
-    extract::{ConnectInfo, DefaultBodyLimit, Path, State, WebSocketUpgrade},
+    extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State, WebSocketUpgrade},
     http::Method,
     response::IntoResponse,
     routing::{delete, get, post},
@@ -17,7 +17,14 @@ use tower_http::{
     services::{ServeDir, ServeFile},
 };
 
-use crate::{base64::Base64, protocol::*};
+use crate::{
+    base64::Base64,
+    envelope::{DeleteEnvelopeParams, EnvelopeEval},
+    error::Error,
+    protocol::*,
+    render::RenderParams,
+    uploads::ChunkParams,
+};
 use crate::{Cli, Server};
 
 pub struct Router {
@@ -35,8 +42,26 @@ impl Router {
             .allow_credentials(false)
             .allow_headers(cors::Any);
 
+        // the body limits follow the map size limit, the multipart routes take several maps.
+        let body_limit = server.max_body_size();
+
         let http_routes = axum::Router::new()
             .route("/maps", get(route_get_maps))
+            .route(
+                "/diff",
+                post(route_post_diff).layer(DefaultBodyLimit::max(2 * body_limit)),
+            )
+            .route(
+                "/merge",
+                post(route_post_merge).layer(DefaultBodyLimit::max(3 * body_limit)),
+            )
+            .route("/uploads", post(route_post_upload))
+            .route(
+                "/uploads/:upload",
+                get(route_get_upload)
+                    .put(route_put_upload)
+                    .delete(route_delete_upload),
+            )
             .route(
                 "/maps/:map",
                 get(route_get_map)
@@ -44,6 +69,13 @@ impl Router {
                     .post(route_post_map)
                     .delete(route_delete_map),
             )
+            .route(
+                "/maps/:map/diff",
+                get(route_get_map_diff).post(route_post_map_diff),
+            )
+            .route("/maps/:map/stats", get(route_get_stats))
+            .route("/maps/:map/render", get(route_get_render))
+            .route("/maps/:map/thumbnail", get(route_get_thumbnail))
             .route("/maps/:map/map/images", get(route_get_images))
             .route("/maps/:map/map/images/:image", get(route_get_image))
             .route(
@@ -54,12 +86,20 @@ impl Router {
                 "/maps/:map/map/envelopes",
                 get(route_get_envelopes).put(route_put_envelope),
             )
+            .route(
+                "/maps/:map/map/envelopes/usage",
+                get(route_get_envelope_usage),
+            )
             .route(
                 "/maps/:map/map/envelopes/:envelope",
                 get(route_get_envelope)
                     .post(route_post_envelope)
                     .delete(route_delete_envelope),
             )
+            .route(
+                "/maps/:map/map/envelopes/:envelope/eval",
+                post(route_post_envelope_eval),
+            )
             .route(
                 "/maps/:map/map/groups",
                 get(route_get_groups).put(route_put_group),
@@ -106,7 +146,7 @@ impl Router {
             .layer(GovernorLayer {
                 config: Arc::new(GovernorConfigBuilder::default().finish().unwrap()),
             })
-            .layer(DefaultBodyLimit::max(2 * 1024 * 1024)) // 2 MiB
+            .layer(DefaultBodyLimit::max(body_limit))
             .layer(cors)
             .with_state(server);
 
@@ -164,7 +204,10 @@ async fn route_websocket(
     log::info!("client {addr} connected");
     log::debug!("client user-agent: `{user_agent}`");
 
-    ws.on_upgrade(move |socket| async move { server.handle_websocket(socket, addr).await })
+    let max_size = server.max_body_size();
+    ws.max_message_size(max_size)
+        .max_frame_size(max_size)
+        .on_upgrade(move |socket| async move { server.handle_websocket(socket, addr).await })
 }
 
 async fn route_get_maps(State(server): State<Arc<Server>>) -> impl IntoResponse {
@@ -206,6 +249,126 @@ async fn route_delete_map(
     server.delete_map(&map)
 }
 
+async fn route_post_upload(
+    State(server): State<Arc<Server>>,
+    Json(size): Json<usize>,
+) -> impl IntoResponse {
+    server.uploads.start(size).map(Json)
+}
+
+async fn route_get_upload(
+    State(server): State<Arc<Server>>,
+    Path(upload): Path<String>,
+) -> impl IntoResponse {
+    server.uploads.get(&upload).map(Json)
+}
+
+async fn route_put_upload(
+    State(server): State<Arc<Server>>,
+    Path(upload): Path<String>,
+    Query(params): Query<ChunkParams>,
+    chunk: Bytes,
+) -> impl IntoResponse {
+    server
+        .uploads
+        .chunk(&upload, params.offset, &chunk)
+        .map(Json)
+}
+
+async fn route_delete_upload(
+    State(server): State<Arc<Server>>,
+    Path(upload): Path<String>,
+) -> impl IntoResponse {
+    server.uploads.cancel(&upload)
+}
+
+async fn route_get_map_diff(
+    State(server): State<Arc<Server>>,
+    Path(map): Path<String>,
+) -> impl IntoResponse {
+    server.get_diff(&map, None).map(Json)
+}
+
+async fn route_post_map_diff(
+    State(server): State<Arc<Server>>,
+    Path(map): Path<String>,
+    file: Bytes,
+) -> impl IntoResponse {
+    server.get_diff(&map, Some(&file)).map(Json)
+}
+
+// reads the multipart fields with the given names, in that order.
+async fn multipart_files<const N: usize>(
+    mut multipart: Multipart,
+    names: [&str; N],
+) -> Result<[Bytes; N], Error> {
+    let mut files: [Option<Bytes>; N] = std::array::from_fn(|_| None);
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| Error::BadRequest(e.to_string()))?
+    {
+        let Some(i) = names.iter().position(|n| Some(*n) == field.name()) else {
+            continue;
+        };
+        let bytes = field
+            .bytes()
+            .await
+            .map_err(|e| Error::BadRequest(e.to_string()))?;
+        files[i] = Some(bytes);
+    }
+
+    let mut res: [Bytes; N] = std::array::from_fn(|_| Bytes::new());
+    for (i, file) in files.into_iter().enumerate() {
+        res[i] = file.ok_or_else(|| Error::BadRequest(format!("missing field '{}'", names[i])))?;
+    }
+    Ok(res)
+}
+
+async fn route_post_diff(
+    State(server): State<Arc<Server>>,
+    multipart: Multipart,
+) -> impl IntoResponse {
+    let [base, other] = multipart_files(multipart, ["base", "other"]).await?;
+    server.diff_files(&base, &other).map(Json)
+}
+
+async fn route_post_merge(
+    State(server): State<Arc<Server>>,
+    multipart: Multipart,
+) -> impl IntoResponse {
+    let [base, ours, theirs] = multipart_files(multipart, ["base", "ours", "theirs"]).await?;
+    server.merge_files(&base, &ours, &theirs).map(Json)
+}
+
+async fn route_get_stats(
+    State(server): State<Arc<Server>>,
+    Path(map): Path<String>,
+) -> impl IntoResponse {
+    server.get_stats(&map).map(Json)
+}
+
+async fn route_get_render(
+    State(server): State<Arc<Server>>,
+    Path(map): Path<String>,
+    Query(params): Query<RenderParams>,
+) -> impl IntoResponse {
+    // rendering is cpu-bound
+    tokio::task::spawn_blocking(move || server.render_map(&map, &params))
+        .await
+        .map_err(|e| Error::Internal(e.to_string().into()))?
+}
+
+async fn route_get_thumbnail(
+    State(server): State<Arc<Server>>,
+    Path(map): Path<String>,
+) -> impl IntoResponse {
+    tokio::task::spawn_blocking(move || server.get_thumbnail(&map))
+        .await
+        .map_err(|e| Error::Internal(e.to_string().into()))?
+}
+
 async fn route_get_images(
     State(server): State<Arc<Server>>,
     Path(map): Path<String>,
@@ -250,6 +413,13 @@ async fn route_put_envelope(
     server.put_envelope(&map, part_env)
 }
 
+async fn route_get_envelope_usage(
+    State(server): State<Arc<Server>>,
+    Path(map): Path<String>,
+) -> impl IntoResponse {
+    server.get_envelope_usage(&map).map(Json)
+}
+
 async fn route_get_envelope(
     State(server): State<Arc<Server>>,
     Path((map, env)): Path<(String, u16)>,
@@ -257,6 +427,14 @@ async fn route_get_envelope(
     server.get_envelope(&map, env).map(Json)
 }
 
+async fn route_post_envelope_eval(
+    State(server): State<Arc<Server>>,
+    Path((map, envelope)): Path<(String, u16)>,
+    Json(params): Json<EnvelopeEval>,
+) -> impl IntoResponse {
+    server.eval_envelope(&map, envelope, &params).map(Json)
+}
+
 async fn route_post_envelope(
     State(server): State<Arc<Server>>,
     Path((map, env)): Path<(String, u16)>,
@@ -268,8 +446,9 @@ async fn route_post_envelope(
 async fn route_delete_envelope(
     State(server): State<Arc<Server>>,
     Path((map, env)): Path<(String, u16)>,
+    Query(params): Query<DeleteEnvelopeParams>,
 ) -> impl IntoResponse {
-    server.delete_envelope(&map, env)
+    server.delete_envelope(&map, env, params.force)
 }
 
 async fn route_get_groups(
@@ -0,0 +1,226 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use regex::Regex;
+use tokio::{io::AsyncReadExt, process::Command};
+use uuid::Uuid;
+
+use crate::{
+    cli::Cli,
+    error::Error,
+    protocol::{AutomapperDiagnostic, Severity, Span},
+};
+
+// Compilation of Rules++ files with the external rpp executable. A rules file can
+// make rpp run for a very long time or use a lot of memory, so it runs with a
+// timeout, a limited output and (on unix) resource limits.
+
+pub struct Rpp {
+    /// Directory containing the rpp executable, base.r and base.p.
+    pub path: PathBuf,
+    pub timeout: Duration,
+    /// In bytes, for both the error output and the generated .rules file.
+    pub max_output: u64,
+    /// In MiB.
+    pub max_memory: u64,
+}
+
+impl Rpp {
+    pub fn new(cli: &Cli) -> Option<Self> {
+        cli.rpp_path.as_ref().map(|path| Rpp {
+            path: path.clone(),
+            timeout: Duration::from_secs(cli.rpp_timeout),
+            max_output: cli.rpp_max_output * 1024,
+            max_memory: cli.rpp_max_memory,
+        })
+    }
+
+    fn command(&self, path: &Path) -> Result<Command, Error> {
+        let root = path.parent().ok_or(Error::Internal("no parent".into()))?;
+
+        let in_fname = path
+            .file_name()
+            .ok_or(Error::Internal("no file name".into()))?
+            .to_string_lossy();
+        let out_fname = format!(
+            "{}.rules",
+            path.file_stem()
+                .ok_or(Error::Internal("no file name".into()))?
+                .to_string_lossy()
+        );
+
+        let rpp_exe = self.path.join("rpp");
+        let rpp_base_r = self.path.join("base.r");
+        let rpp_base_p = self.path.join("base.p");
+
+        let mut cmd = Command::new(&rpp_exe);
+        cmd.current_dir(root)
+            .args([
+                "--output",
+                &out_fname,
+                "--memory",
+                &self.max_memory.to_string(),
+                "--include",
+                &rpp_base_r.to_string_lossy(),
+                "--include",
+                &rpp_base_p.to_string_lossy(),
+                "--no-pause",
+                &in_fname,
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        #[cfg(unix)]
+        {
+            let limits = [
+                (libc::RLIMIT_AS, (self.max_memory + 64) * 1024 * 1024),
+                (libc::RLIMIT_CPU, self.timeout.as_secs() + 1),
+                (libc::RLIMIT_FSIZE, self.max_output),
+            ];
+            // SAFETY: setrlimit is async-signal-safe, and nothing is allocated in the closure.
+            unsafe {
+                cmd.pre_exec(move || {
+                    for (resource, limit) in limits {
+                        let rlimit = libc::rlimit {
+                            rlim_cur: limit as libc::rlim_t,
+                            rlim_max: limit as libc::rlim_t,
+                        };
+                        if libc::setrlimit(resource, &rlimit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        Ok(cmd)
+    }
+
+    /// Compiles the .rpp file at `path` into a .rules file next to it.
+    pub async fn compile(&self, path: &Path) -> Result<(), Error> {
+        let mut cmd = self.command(path)?;
+        log::debug!("rpp: {cmd:?}");
+
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| Error::Internal(e.to_string().into()))?;
+
+        let mut stderr = Vec::new();
+        let mut pipe = child.stderr.take().unwrap().take(self.max_output + 1);
+
+        let res = tokio::time::timeout(self.timeout, async {
+            pipe.read_to_end(&mut stderr).await?;
+            // rpp blocks on its remaining output, there is no need to wait for it.
+            if stderr.len() as u64 > self.max_output {
+                child.kill().await?;
+            }
+            child.wait().await
+        })
+        .await;
+
+        // the child is killed when dropped.
+        let status = match res {
+            Err(_) => {
+                log::info!("rpp: killed after {:?}", self.timeout);
+                return Err(Error::Automapper(format!(
+                    "Rules++ compilation stopped, it took more than {} seconds",
+                    self.timeout.as_secs()
+                )));
+            }
+            Ok(Err(e)) => return Err(Error::Internal(e.to_string().into())),
+            Ok(Ok(status)) => status,
+        };
+
+        if stderr.len() as u64 > self.max_output {
+            return Err(Error::Automapper(format!(
+                "Rules++ compilation stopped, its output is larger than {} KiB",
+                self.max_output / 1024
+            )));
+        }
+
+        let stderr = String::from_utf8_lossy(&stderr);
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(_) => {
+                log::info!("rpp: {stderr}");
+                Err(Error::Automapper(stderr.into_owned()))
+            }
+            None => {
+                log::info!("rpp: {status}, {stderr}");
+                Err(Error::Automapper(killed_reason(&status, self)))
+            }
+        }
+    }
+
+    /// Compiles a Rules++ source that is not saved, in a temporary directory, and returns
+    /// the generated rules.
+    pub async fn compile_source(&self, name: &str, source: &str) -> Result<String, Error> {
+        let dir = std::env::temp_dir().join(format!("twwe-rpp-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).map_err(|e| Error::Internal(e.to_string().into()))?;
+
+        let path = dir.join(format!("{name}.rpp"));
+        let res = match std::fs::write(&path, source) {
+            Ok(()) => self.compile(&path).await.and_then(|()| {
+                std::fs::read_to_string(path.with_extension("rules"))
+                    .map_err(|e| Error::Automapper(e.to_string()))
+            }),
+            Err(e) => Err(Error::Internal(e.to_string().into())),
+        };
+
+        std::fs::remove_dir_all(&dir).ok();
+        res
+    }
+}
+
+#[cfg(unix)]
+fn killed_reason(status: &std::process::ExitStatus, rpp: &Rpp) -> String {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.signal() {
+        Some(libc::SIGXCPU) => format!(
+            "Rules++ compilation stopped, it took more than {} seconds",
+            rpp.timeout.as_secs()
+        ),
+        Some(libc::SIGXFSZ) => format!(
+            "Rules++ compilation stopped, the generated rules are larger than {} KiB",
+            rpp.max_output / 1024
+        ),
+        Some(libc::SIGSEGV | libc::SIGABRT | libc::SIGKILL) => format!(
+            "Rules++ compilation stopped, it probably used more than {} MiB of memory",
+            rpp.max_memory
+        ),
+        _ => format!("Rules++ compilation stopped: {status}"),
+    }
+}
+
+#[cfg(not(unix))]
+fn killed_reason(status: &std::process::ExitStatus, _rpp: &Rpp) -> String {
+    format!("Rules++ compilation stopped: {status}")
+}
+
+/// Extracts the diagnostics from the error output of rpp.
+pub fn rpp_diagnostics(output: &str) -> Vec<AutomapperDiagnostic> {
+    let reg = Regex::new(r"\[(\d+):(\d+)-(\d+):(\d+)\]\s*(.+)").unwrap();
+    output
+        .split('\n')
+        .filter_map(|s| {
+            let caps = reg.captures(s)?;
+            Some(AutomapperDiagnostic {
+                span: Span {
+                    line_start: caps[1].parse().ok()?,
+                    col_start: caps[2].parse().ok()?,
+                    line_end: caps[3].parse().ok()?,
+                    col_end: caps[4].parse().ok()?,
+                },
+                msg: caps[5].to_string(),
+                severity: Severity::Error,
+            })
+        })
+        .collect()
+}
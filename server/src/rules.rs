@@ -0,0 +1,613 @@
+use twmap::{
+    automapper::{
+        Automapper, Chance, Condition, Config, IndexRule, Rule, Run, TileCondition,
+        MAX_CONFIG_NAME_LENGTH,
+    },
+    Tile, TileFlags,
+};
+use vek::Vec2;
+
+use crate::{
+    error::Error,
+    protocol::{AutomapperDiagnostic, Severity, Span},
+};
+
+// Parser for DDNet .rules automapper files, see doc/automap.md for the grammar.
+// Unlike the parser of twmap, it does not stop at the first error and reports all
+// errors and warnings with their location, so that they can be shown in the editor.
+// Errors are what cannot be automapped, warnings are what DDNet accepts but is
+// most likely a mistake.
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    str: &'a str,
+    /// Character offset in the line.
+    col: usize,
+}
+
+impl Token<'_> {
+    fn end(&self) -> usize {
+        self.col + self.str.chars().count()
+    }
+}
+
+struct Line<'a> {
+    /// Line number, starting at 1.
+    number: u32,
+    len: usize,
+    tokens: Vec<Token<'a>>,
+    next: usize,
+}
+
+impl<'a> Line<'a> {
+    fn new(number: u32, line: &'a str) -> Self {
+        let mut tokens = Vec::new();
+        // byte and character offsets of the current token.
+        let mut start = None;
+        let mut len = 0;
+        for (col, (byte, c)) in line.char_indices().enumerate() {
+            len = col + 1;
+            if c == ' ' || c == '\t' {
+                if let Some((s_byte, s_col)) = start.take() {
+                    tokens.push(Token {
+                        str: &line[s_byte..byte],
+                        col: s_col,
+                    });
+                }
+            } else if start.is_none() {
+                if c == '#' {
+                    // comments end the line.
+                    len = col;
+                    break;
+                }
+                start = Some((byte, col));
+            }
+        }
+        if let Some((s_byte, s_col)) = start {
+            tokens.push(Token {
+                str: &line[s_byte..],
+                col: s_col,
+            });
+        }
+
+        Line {
+            number,
+            len,
+            tokens,
+            next: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.next).copied();
+        self.next += 1;
+        token
+    }
+
+    fn span(&self, col_start: usize, col_end: usize) -> Span {
+        Span {
+            line_start: self.number,
+            col_start: col_start as u32 + 1,
+            line_end: self.number,
+            col_end: col_end as u32 + 1,
+        }
+    }
+
+    fn token_span(&self, token: Token) -> Span {
+        self.span(token.col, token.end())
+    }
+
+    // where a missing token would be.
+    fn end_span(&self) -> Span {
+        self.span(self.len, self.len)
+    }
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<AutomapperDiagnostic>);
+
+impl Diagnostics {
+    fn error(&mut self, span: Span, msg: impl Into<String>) {
+        self.0.push(AutomapperDiagnostic {
+            span,
+            msg: msg.into(),
+            severity: Severity::Error,
+        });
+    }
+
+    fn warning(&mut self, span: Span, msg: impl Into<String>) {
+        self.0.push(AutomapperDiagnostic {
+            span,
+            msg: msg.into(),
+            severity: Severity::Warning,
+        });
+    }
+
+    fn trailing(&mut self, line: &mut Line) {
+        if let Some(first) = line.next() {
+            let last = line.tokens.last().copied().unwrap_or(first);
+            self.warning(
+                line.span(first.col, last.end()),
+                "unexpected input at the end of the line, it is ignored",
+            );
+        }
+    }
+
+    fn tile_id(&mut self, line: &mut Line, what: &str) -> Option<u8> {
+        let Some(token) = line.next() else {
+            self.error(line.end_span(), format!("expected {what}"));
+            return None;
+        };
+        match token.str.parse::<i64>() {
+            Ok(id @ 0..=255) => Some(id as u8),
+            Ok(_) => {
+                self.error(line.token_span(token), "tile id out of range (0 to 255)");
+                None
+            }
+            Err(_) => {
+                self.error(line.token_span(token), format!("expected {what}"));
+                None
+            }
+        }
+    }
+
+    fn offset(&mut self, line: &mut Line, what: &str) -> Option<i32> {
+        let Some(token) = line.next() else {
+            self.error(line.end_span(), format!("expected {what}"));
+            return None;
+        };
+        match token.str.parse::<i32>() {
+            Ok(offset) => Some(offset),
+            Err(_) => {
+                self.error(line.token_span(token), format!("expected {what}"));
+                None
+            }
+        }
+    }
+}
+
+fn flag(token: &str) -> Option<TileFlags> {
+    match token {
+        "XFLIP" => Some(TileFlags::FLIP_X),
+        "YFLIP" => Some(TileFlags::FLIP_Y),
+        "ROTATE" => Some(TileFlags::ROTATE),
+        _ => None,
+    }
+}
+
+fn parse_chance(str: &str) -> Option<Chance> {
+    match str.strip_suffix('%') {
+        Some(str) => {
+            let p = str.parse::<f32>().ok()?;
+            (p > 0. && p < 100.).then_some(Chance::Percentage(p))
+        }
+        None => {
+            let n = str.parse::<f32>().ok()?;
+            (n > 1. && n.is_finite()).then_some(Chance::OneOutOf(n))
+        }
+    }
+}
+
+struct RuleState {
+    rule: IndexRule,
+    span: Span,
+    no_default: bool,
+    random: bool,
+}
+
+struct RunState {
+    run: Run,
+    rules: Vec<RuleState>,
+    no_layer_copy: bool,
+}
+
+struct ConfigState {
+    config: Config,
+    runs: Vec<RunState>,
+    span: Span,
+}
+
+struct Parser {
+    diagnostics: Diagnostics,
+    configs: Vec<ConfigState>,
+}
+
+impl Parser {
+    fn run(&mut self) -> Option<&mut RunState> {
+        self.configs.last_mut()?.runs.last_mut()
+    }
+
+    fn rule(&mut self) -> Option<&mut RuleState> {
+        self.run()?.rules.last_mut()
+    }
+
+    fn header(&mut self, line: &Line, str: &str) {
+        let start = line.tokens[0].col;
+        let span = line.span(start, line.len.max(start + 1));
+        // the name may contain spaces and '#', so the raw line is used.
+        let header = &str.trim_start()[1..];
+        let name = match header.find(']') {
+            Some(i) => {
+                let rest = header[i + 1..].trim();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    self.diagnostics.warning(
+                        span,
+                        "unexpected input after the config name, it is ignored",
+                    );
+                }
+                header[..i].to_owned()
+            }
+            None => {
+                self.diagnostics
+                    .error(span, "missing \"]\" at the end of the config name");
+                header.trim_end().to_owned()
+            }
+        };
+        if name.len() > MAX_CONFIG_NAME_LENGTH {
+            self.diagnostics.error(
+                span,
+                format!("config name too long, it must be at most {MAX_CONFIG_NAME_LENGTH} bytes"),
+            );
+        }
+        if self.configs.iter().any(|c| c.config.name == name) {
+            self.diagnostics
+                .warning(span, format!("there is already a config named \"{name}\""));
+        }
+
+        self.configs.push(ConfigState {
+            config: Config {
+                name,
+                runs: Vec::new(),
+            },
+            runs: Vec::new(),
+            span,
+        });
+        self.new_run();
+    }
+
+    fn new_run(&mut self) {
+        if let Some(config) = self.configs.last_mut() {
+            config.runs.push(RunState {
+                run: Run {
+                    layer_copy: true,
+                    rules: Vec::new(),
+                },
+                rules: Vec::new(),
+                no_layer_copy: false,
+            });
+        }
+    }
+
+    fn index(&mut self, line: &mut Line, span: Span) {
+        let Some(id) = self.diagnostics.tile_id(line, "a tile id after \"Index\"") else {
+            return;
+        };
+        let mut flags = TileFlags::empty();
+        while let Some(token) = line.next() {
+            match flag(token.str) {
+                Some(f) if flags.contains(f) => self.diagnostics.warning(
+                    line.token_span(token),
+                    format!("duplicate \"{}\"", token.str),
+                ),
+                Some(f) => flags |= f,
+                None => self.diagnostics.error(
+                    line.token_span(token),
+                    format!(
+                        "unexpected \"{}\", expected \"XFLIP\", \"YFLIP\" or \"ROTATE\"",
+                        token.str
+                    ),
+                ),
+            }
+        }
+
+        if let Some(run) = self.run() {
+            run.rules.push(RuleState {
+                rule: IndexRule {
+                    tile: Tile::new(id, flags),
+                    default_rule: true,
+                    chance: Chance::Always,
+                    conditions: Vec::new(),
+                },
+                span,
+                no_default: false,
+                random: false,
+            });
+        }
+    }
+
+    fn tile_conditions(&mut self, line: &mut Line) -> Option<Vec<TileCondition>> {
+        let mut conditions = Vec::new();
+        loop {
+            let id = match line.peek() {
+                Some(t) if t.str == "-1" => {
+                    line.next();
+                    None
+                }
+                _ => Some(
+                    self.diagnostics
+                        .tile_id(line, "a tile id or -1 (outside)")?,
+                ),
+            };
+
+            let mut flags = None;
+            let mut another = false;
+            while let Some(token) = line.next() {
+                if token.str == "OR" {
+                    another = true;
+                    break;
+                }
+                let span = line.token_span(token);
+                if token.str == "NONE" {
+                    if flags.is_some_and(|f: TileFlags| !f.is_empty()) {
+                        self.diagnostics
+                            .warning(span, "\"NONE\" discards the previous flags");
+                    } else if flags.is_some() {
+                        self.diagnostics.warning(span, "duplicate \"NONE\"");
+                    }
+                    flags = Some(TileFlags::empty());
+                    continue;
+                }
+                match flag(token.str) {
+                    Some(f) => {
+                        let current = flags.get_or_insert(TileFlags::empty());
+                        if current.contains(f) {
+                            self.diagnostics
+                                .warning(span, format!("duplicate \"{}\"", token.str));
+                        }
+                        current.insert(f);
+                    }
+                    None => {
+                        self.diagnostics.error(
+                            span,
+                            format!(
+                                "unexpected \"{}\", expected \"XFLIP\", \"YFLIP\", \"ROTATE\", \"NONE\" or \"OR\"",
+                                token.str
+                            ),
+                        );
+                        return None;
+                    }
+                }
+            }
+
+            conditions.push(match (id, flags) {
+                (None, _) => TileCondition::Outside,
+                (Some(id), None) => TileCondition::Index(id),
+                (Some(id), Some(flags)) => TileCondition::Tile(Tile::new(id, flags)),
+            });
+
+            if !another {
+                return Some(conditions);
+            }
+        }
+    }
+
+    fn pos(&mut self, line: &mut Line) {
+        let x = self.diagnostics.offset(line, "the x offset after \"Pos\"");
+        let y = self.diagnostics.offset(line, "the y offset after \"Pos\"");
+        let (Some(x), Some(y)) = (x, y) else {
+            return;
+        };
+
+        let condition = match line.next() {
+            Some(t) if t.str == "EMPTY" => Condition::Empty,
+            Some(t) if t.str == "FULL" => Condition::Full,
+            Some(t) if t.str == "INDEX" || t.str == "NOTINDEX" => {
+                let Some(list) = self.tile_conditions(line) else {
+                    return;
+                };
+                if t.str == "INDEX" {
+                    Condition::WhiteList(list)
+                } else {
+                    Condition::BlackList(list)
+                }
+            }
+            token => {
+                let span = match token {
+                    Some(token) => line.token_span(token),
+                    None => line.end_span(),
+                };
+                self.diagnostics.error(
+                    span,
+                    "expected \"EMPTY\", \"FULL\", \"INDEX\" or \"NOTINDEX\"",
+                );
+                return;
+            }
+        };
+        self.diagnostics.trailing(line);
+
+        if let Some(rule) = self.rule() {
+            let offset = Vec2::new(x, y);
+            if offset == Vec2::zero() {
+                rule.rule.default_rule = false;
+            }
+            rule.rule.conditions.push(Rule { offset, condition });
+        }
+    }
+
+    fn random(&mut self, line: &mut Line, span: Span) {
+        let Some(token) = line.next() else {
+            self.diagnostics
+                .error(line.end_span(), "expected a number after \"Random\"");
+            return;
+        };
+        let Some(chance) = parse_chance(token.str) else {
+            self.diagnostics.error(
+                line.token_span(token),
+                "expected a number greater than 1 or a percentage between 0% and 100%",
+            );
+            return;
+        };
+        self.diagnostics.trailing(line);
+
+        let rule = self.rule().unwrap();
+        let duplicate = rule.random;
+        rule.random = true;
+        rule.rule.chance = chance;
+        if duplicate {
+            self.diagnostics
+                .warning(span, "\"Random\" replaces the previous one of this rule");
+        }
+    }
+
+    fn line(&mut self, mut line: Line, str: &str) {
+        let Some(keyword) = line.next() else {
+            return;
+        };
+        let span = line.token_span(keyword);
+
+        if keyword.str.starts_with('[') {
+            return self.header(&line, str);
+        }
+
+        if self.configs.is_empty() {
+            self.diagnostics
+                .error(span, "expected a config name first, e.g. \"[Default]\"");
+            return;
+        }
+
+        let has_rule = self.rule().is_some();
+        let needs_rule = ["Pos", "Random", "NoDefaultRule"].contains(&keyword.str);
+        if needs_rule && !has_rule {
+            self.diagnostics.error(
+                span,
+                format!("\"{}\" must follow an \"Index\" rule", keyword.str),
+            );
+            return;
+        }
+
+        match keyword.str {
+            "NewRun" => {
+                let run = self.run().unwrap();
+                if run.rules.is_empty() {
+                    self.diagnostics.warning(span, "the previous run is empty");
+                }
+                self.diagnostics.trailing(&mut line);
+                self.new_run();
+            }
+            "NoLayerCopy" => {
+                let run = self.run().unwrap();
+                let (misplaced, duplicate) = (!run.rules.is_empty(), run.no_layer_copy);
+                run.no_layer_copy = true;
+                run.run.layer_copy = false;
+                if duplicate {
+                    self.diagnostics.warning(span, "duplicate \"NoLayerCopy\"");
+                } else if misplaced {
+                    self.diagnostics.warning(
+                        span,
+                        "\"NoLayerCopy\" applies to the whole run, it should be at its start",
+                    );
+                }
+                self.diagnostics.trailing(&mut line);
+            }
+            "Index" => self.index(&mut line, span),
+            "Pos" => self.pos(&mut line),
+            "Random" => self.random(&mut line, span),
+            "NoDefaultRule" => {
+                let rule = self.rule().unwrap();
+                let duplicate = rule.no_default;
+                rule.no_default = true;
+                rule.rule.default_rule = false;
+                if duplicate {
+                    self.diagnostics
+                        .warning(span, "duplicate \"NoDefaultRule\"");
+                }
+                self.diagnostics.trailing(&mut line);
+            }
+            _ => self
+                .diagnostics
+                .error(span, format!("unknown keyword \"{}\"", keyword.str)),
+        }
+    }
+
+    fn finish(mut self, name: String) -> (Automapper, Vec<AutomapperDiagnostic>) {
+        let mut configs = Vec::new();
+        for mut config in self.configs {
+            let mut rules_count = 0;
+            for run in config.runs {
+                check_reachable(&run, &mut self.diagnostics);
+                rules_count += run.rules.len();
+                let mut run_out = run.run;
+                run_out.rules = run.rules.into_iter().map(|r| r.rule).collect();
+                config.config.runs.push(run_out);
+            }
+            if rules_count == 0 {
+                self.diagnostics
+                    .warning(config.span, "this config has no rules");
+            }
+            configs.push(config.config);
+        }
+
+        let mut diagnostics = self.diagnostics.0;
+        diagnostics.sort_by_key(|d| (d.span.line_start, d.span.col_start));
+        (Automapper { name, configs }, diagnostics)
+    }
+}
+
+// whether `later` always applies where `earlier` does, and thus overwrites it.
+fn overrides(earlier: &IndexRule, later: &IndexRule) -> bool {
+    let full_center = |r: &IndexRule| {
+        r.default_rule
+            || r.conditions
+                .iter()
+                .any(|c| c.offset == Vec2::zero() && c.condition == Condition::Full)
+    };
+    matches!(later.chance, Chance::Always)
+        && (!later.default_rule || full_center(earlier))
+        && later
+            .conditions
+            .iter()
+            .all(|c| earlier.conditions.contains(c))
+}
+
+// with a layer copy, the rules of a run all see the same tiles, so a rule followed by a
+// less restrictive one is never visible.
+fn check_reachable(run: &RunState, diagnostics: &mut Diagnostics) {
+    if !run.run.layer_copy {
+        return;
+    }
+    for (i, earlier) in run.rules.iter().enumerate() {
+        if let Some(later) = run.rules[i + 1..]
+            .iter()
+            .find(|later| overrides(&earlier.rule, &later.rule))
+        {
+            diagnostics.warning(
+                earlier.span,
+                format!(
+                    "this rule is unreachable, it is always overwritten by the rule at line {}",
+                    later.span.line_start
+                ),
+            );
+        }
+    }
+}
+
+/// Parses a .rules file. The automapper is only usable if there are no errors in the diagnostics.
+pub fn parse_rules(name: String, file: &str) -> (Automapper, Vec<AutomapperDiagnostic>) {
+    let mut parser = Parser {
+        diagnostics: Diagnostics::default(),
+        configs: Vec::new(),
+    };
+
+    for (i, str) in file.lines().enumerate() {
+        let line = Line::new(i as u32 + 1, str);
+        parser.line(line, str);
+    }
+
+    parser.finish(name)
+}
+
+/// Parses a .rules file, failing on the first error.
+pub fn load_rules(name: String, file: &str) -> Result<Automapper, Error> {
+    let (automapper, diagnostics) = parse_rules(name, file);
+    match diagnostics.iter().find(|d| d.severity == Severity::Error) {
+        Some(d) => Err(Error::Automapper(format!(
+            "line {}: {}",
+            d.span.line_start, d.msg
+        ))),
+        None => Ok(automapper),
+    }
+}
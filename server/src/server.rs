@@ -9,19 +9,41 @@ use std::{
 };
 
 use axum::extract::ws::{Message as WebSocketMessage, WebSocket};
-use futures::{channel::mpsc::unbounded, SinkExt, StreamExt, TryStreamExt};
+use futures::{channel::mpsc::unbounded, SinkExt, StreamExt};
 use image::ImageFormat;
-use regex::Regex;
 
 use crate::{
+    automap::{AutomapDiff, AutomapPreview, AutomapRegion, LayerAutomapper},
     base64::Base64,
     checks::PartialCheck,
     cli::Cli,
+    envelope::{envelopes_usage, eval_envelope, EnvSample, EnvelopeEval, EnvelopeUsage},
     error::Error,
+    library::{ImportedAutomapper, Library},
     map_cfg::MapAccess,
+    map_copy::{CopyReport, Fragment},
+    map_diff::{diff_maps, MapDiff},
+    map_merge::{merge_maps, MergedMap},
+    map_optimize::{optimize_map, OptimizeReport},
+    map_stats::{map_stats, MapStats},
+    map_sync::{
+        check_creatable, copy_requests, create_group_requests, create_layer_requests,
+        create_quads_requests, edit_quads_requests, layer_tiles_request, move_group_request,
+        move_layer_request, tiles_request,
+    },
+    map_transform::{transform_map, MapTransform},
+    mapres::Mapres,
     protocol::*,
-    room::{Peer, Room},
+    quads_edit::{edit_quads, QuadsOp},
+    render::{encode_png, map_images, render_map, RenderParams, Viewport, THUMBNAIL_SIZE},
+    room::{load_map, Peer, Room},
+    rpp::{rpp_diagnostics, Rpp},
+    rules::{load_rules, parse_rules},
+    tiles_find::{find_tiles, replace_tiles, TilesFound, TilesQuery, TilesReplacement},
+    tiles_remap::{RemapReport, RemapTiles, Remapping, TileMap},
+    tw_automapper::{load_tw_automapper, parse_tw_automapper},
     twmap_map_checks::InternalMapChecking,
+    uploads::Uploads,
     util::{macros::apply_partial, *},
 };
 
@@ -36,9 +58,12 @@ use crate::bridge::Bridge;
 
 pub struct Server {
     pub rooms: Mutex<HashMap<String, Arc<Room>>>,
-    pub rpp_path: Option<PathBuf>,
+    pub rpp: Option<Rpp>,
+    pub library: Library,
     pub maps_dir: Option<PathBuf>,
     pub data_dir: Option<PathBuf>,
+    pub mapres: Mapres,
+    pub uploads: Uploads,
     pub max_maps: usize,
     pub max_map_size: usize, // in bytes
     pub max_peers: usize,
@@ -53,9 +78,12 @@ impl Server {
     pub fn new(cli: &Cli) -> Self {
         Server {
             rooms: Mutex::new(HashMap::new()),
-            rpp_path: cli.rpp_path.clone(),
+            rpp: Rpp::new(cli),
+            library: Library::new(cli.library_dir.clone()),
             maps_dir: cli.maps_dirs.first().cloned(),
             data_dir: cli.data_dirs.first().cloned(),
+            mapres: Mapres::new(&cli.data_dirs),
+            uploads: Uploads::new(cli.max_map_size * 1024),
             max_maps: cli.max_maps,
             max_map_size: cli.max_map_size * 1024,
             max_peers: cli.max_connections,
@@ -71,6 +99,11 @@ impl Server {
         self.rooms.lock().expect("failed to lock rooms")
     }
 
+    /// Largest request body: a map encoded in base64, with some room for the rest of the request.
+    pub fn max_body_size(&self) -> usize {
+        self.max_map_size.div_ceil(3) * 4 + 64 * 1024
+    }
+
     fn room(&self, name: &str) -> Result<Arc<Room>, Error> {
         self.rooms()
             .get(name)
@@ -118,6 +151,13 @@ impl Server {
         }
     }
 
+    // sends the requests recreating a change to the peers, see `map_sync`.
+    pub(crate) fn broadcast_requests(&self, room: &Room, reqs: impl IntoIterator<Item = Request>) {
+        for req in reqs {
+            self.broadcast_to_room(room, Message::Request(req));
+        }
+    }
+
     pub(crate) fn broadcast_to_others(&self, peer: &Peer, content: Message) {
         let packet = SendPacket {
             timestamp: timestamp_now(),
@@ -144,7 +184,11 @@ impl Server {
         }
     }
 
-    pub(crate) fn do_request(&self, peer: &mut Peer, req: Request) -> Result<Response, Error> {
+    pub(crate) async fn do_request(
+        &self,
+        peer: &mut Peer,
+        req: Request,
+    ) -> Result<Response, Error> {
         let map_name = peer
             .room
             .as_deref()
@@ -174,6 +218,12 @@ impl Server {
                 GetReq::Envelope(e) => self
                     .get_envelope(map_name?, e)
                     .map(|r| Response::Envelope(Box::new(r))),
+                GetReq::EnvelopeEval(e, params) => self
+                    .eval_envelope(map_name?, e, &params)
+                    .map(Response::EnvelopeSamples),
+                GetReq::EnvelopeUsage => self
+                    .get_envelope_usage(map_name?)
+                    .map(Response::EnvelopeUsage),
                 GetReq::Groups => self.get_groups(map_name?).map(Response::Groups),
                 GetReq::Group(g) => self
                     .get_group(map_name?, g)
@@ -188,10 +238,23 @@ impl Server {
                 GetReq::Quad(g, l, q) => self
                     .get_quad(map_name?, g, l, q)
                     .map(|r| Response::Quad(Box::new(r))),
+                GetReq::FindTiles(query) => self
+                    .find_tiles(map_name?, &query)
+                    .map(|r| Response::FoundTiles(Box::new(r))),
                 GetReq::Automappers => self.get_automappers(map_name?).map(Response::Automappers),
+                GetReq::AutomapPreview(g, l, preview) => self
+                    .preview_automapper(map_name?, g, l, &preview)
+                    .await
+                    .map(|d| Response::Automapped(Box::new(d))),
                 GetReq::Automapper(am) => self
                     .get_automapper(map_name?, &am)
                     .map(Response::Automapper),
+                GetReq::Diff(file) => self
+                    .get_diff(map_name?, file.as_ref().map(|f| f.0.as_slice()))
+                    .map(|r| Response::Diff(Box::new(r))),
+                GetReq::Stats => self
+                    .get_stats(map_name?)
+                    .map(|r| Response::Stats(Box::new(r))),
             },
             Request::Create(req) => match req {
                 CreateReq::Image(image_name, create) => {
@@ -209,22 +272,58 @@ impl Server {
                 }
                 CreateReq::Automapper(am, file) => self
                     .put_automapper(map_name?, &am, &file)
+                    .await
                     .map(Response::AutomapperDiagnostics),
             },
             Request::Edit(req) => match req {
-                EditReq::Config(req) => self.edit_config(map_name?, *req),
-                EditReq::Info(req) => self.edit_info(map_name?, *req),
-                EditReq::Envelope(e, req) => self.edit_envelope(map_name?, e, *req),
-                EditReq::Group(g, req) => self.edit_group(map_name?, g, *req),
-                EditReq::Layer(g, l, req) => self.edit_layer(map_name?, g, l, *req),
-                EditReq::Tiles(g, l, req) => self.edit_tiles(map_name?, g, l, *req),
-                EditReq::Quad(g, l, q, req) => self.edit_quad(map_name?, g, l, q, *req),
-                EditReq::Automap(g, l) => self.apply_automapper(map_name?, g, l),
-            }
-            .map(|()| Response::Ok),
+                EditReq::Config(req) => self.edit_config(map_name?, *req).map(|()| Response::Ok),
+                EditReq::Info(req) => self.edit_info(map_name?, *req).map(|()| Response::Ok),
+                EditReq::Envelope(e, req) => self
+                    .edit_envelope(map_name?, e, *req)
+                    .map(|()| Response::Ok),
+                EditReq::Group(g, req) => {
+                    self.edit_group(map_name?, g, *req).map(|()| Response::Ok)
+                }
+                EditReq::Layer(g, l, req) => self
+                    .edit_layer(map_name?, g, l, *req)
+                    .map(|()| Response::Ok),
+                EditReq::Tiles(g, l, req) => self
+                    .edit_tiles(map_name?, g, l, *req)
+                    .map(|()| Response::Ok),
+                EditReq::Quad(g, l, q, req) => self
+                    .edit_quad(map_name?, g, l, q, *req)
+                    .map(|()| Response::Ok),
+                EditReq::Quads(g, l, quads, op) => self
+                    .edit_quads(map_name?, g, l, &quads, &op)
+                    .map(|()| Response::Ok),
+                EditReq::ReplaceTiles(query, replacement) => self
+                    .replace_tiles(map_name?, &query, &replacement)
+                    .map(Response::Replaced),
+                EditReq::RemapTiles(g, l, req) => self
+                    .remap_tiles(map_name?, g, l, &req)
+                    .map(|r| Response::Remapped(Box::new(r))),
+                EditReq::Automap(g, l) => self
+                    .apply_automapper(map_name?, g, l)
+                    .map(|()| Response::Ok),
+                EditReq::AutomapRegion(g, l, region) => self
+                    .automap_region(map_name?, g, l, &region)
+                    .map(|d| Response::Automapped(Box::new(d))),
+                EditReq::Image(i, part) => {
+                    self.edit_image(map_name?, i, *part).map(|()| Response::Ok)
+                }
+                EditReq::EmbedImage(i) => self.embed_image(map_name?, i).map(|()| Response::Ok),
+                EditReq::UnembedImage(i) => self.unembed_image(map_name?, i).map(|()| Response::Ok),
+                EditReq::Optimize => self
+                    .optimize_map(map_name?)
+                    .map(|r| Response::Optimized(Box::new(r))),
+                EditReq::Transform(transform) => self
+                    .transform_map(map_name?, &transform)
+                    .map(|()| Response::Ok),
+            },
             Request::Delete(req) => match req {
                 DeleteReq::Image(i) => self.delete_image(map_name?, i),
-                DeleteReq::Envelope(e) => self.delete_envelope(map_name?, e),
+                DeleteReq::Envelope(e) => self.delete_envelope(map_name?, e, false),
+                DeleteReq::ForceEnvelope(e) => self.delete_envelope(map_name?, e, true),
                 DeleteReq::Group(g) => self.delete_group(map_name?, g),
                 DeleteReq::Layer(g, l) => self.delete_layer(map_name?, g, l),
                 DeleteReq::Quad(g, l, q) => self.delete_quad(map_name?, g, l, q),
@@ -239,6 +338,37 @@ impl Server {
                 MoveReq::Quad(src, tgt) => self.move_quad(map_name?, src, tgt),
             }
             .map(|()| Response::Ok),
+            Request::Duplicate(req) => match req {
+                DuplicateReq::Group(g) => self.duplicate_group(map_name?, g).map(|g| vec![g]),
+                DuplicateReq::Layer(g, l) => {
+                    self.duplicate_layer(map_name?, g, l).map(|l| vec![g, l])
+                }
+                DuplicateReq::Quads(g, l, quads) => self.duplicate_quads(map_name?, g, l, &quads),
+            }
+            .map(Response::Duplicated),
+            Request::Copy(req) => self
+                .copy(map_name?, &req)
+                .map(|report| Response::Copied(Box::new(report))),
+            Request::Upload(req) => match req {
+                UploadReq::Start(size) => self.uploads.start(size).map(Response::Upload),
+                UploadReq::Status(id) => self.uploads.get(&id).map(Response::Upload),
+                UploadReq::Chunk(id, offset, chunk) => self
+                    .uploads
+                    .chunk(&id, offset, &chunk.0)
+                    .map(Response::Upload),
+                UploadReq::Cancel(id) => self.uploads.cancel(&id).map(|()| Response::Ok),
+            },
+            Request::Library(req) => match req {
+                LibraryReq::List => self.library.list().map(Response::Library),
+                LibraryReq::Import(name, version) => self
+                    .import_automapper(map_name?, &name, version)
+                    .await
+                    .map(|imported| Response::Imported(Box::new(imported))),
+                LibraryReq::Publish(name) => self
+                    .publish_automapper(map_name?, &name)
+                    .await
+                    .map(Response::Version),
+            },
         }
     }
 
@@ -269,21 +399,125 @@ impl Server {
                 Request::Save => {
                     self.broadcast_to_others(peer, Message::Broadcast(Broadcast::Saved))
                 }
+                Request::Edit(EditReq::Optimize) => {
+                    let changed = matches!(&resp, Ok(Response::Optimized(r)) if !r.is_empty());
+                    if let (true, Some(room)) = (changed, &peer.room) {
+                        self.broadcast_to_room(room, Message::Broadcast(Broadcast::Reload))
+                    }
+                }
+                Request::Edit(EditReq::Transform(_)) => {
+                    if let Some(room) = &peer.room {
+                        self.broadcast_to_room(room, Message::Broadcast(Broadcast::Reload))
+                    }
+                }
+                // sent to the room as the basic requests applying them, see `map_sync`.
+                Request::Edit(
+                    EditReq::Quads(..) | EditReq::ReplaceTiles(..) | EditReq::RemapTiles(..),
+                ) => (),
                 Request::Create(_) | Request::Edit(_) | Request::Delete(_) | Request::Move(_) => {
                     self.broadcast_to_others(peer, Message::Request(packet.content.clone()))
                 }
-                Request::ListMaps | Request::GetMap(_) | Request::Cursor(_) | Request::Get(_) => (),
+                Request::ListMaps
+                | Request::GetMap(_)
+                | Request::Cursor(_)
+                | Request::Get(_)
+                | Request::Upload(_)
+                | Request::Duplicate(_)
+                | Request::Copy(_)
+                | Request::Library(_) => (),
+            }
+        }
+    }
+
+    // images uploaded in chunks are replaced with their content, so that the
+    // request broadcast to the other peers is self-contained. Returns the id of the
+    // upload, to remove it once the request succeeded.
+    fn resolve_uploads(&self, req: &mut Request) -> Result<Option<String>, Error> {
+        let image = match req {
+            Request::Create(CreateReq::Image(_, image)) => image,
+            Request::Edit(EditReq::Image(_, part)) => match &mut part.data {
+                Some(image) => image,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+
+        match image {
+            Image::Upload { upload_id } => {
+                let upload_id = upload_id.clone();
+                *image = Image::Embedded(Base64(self.uploads.data(&upload_id)?));
+                Ok(Some(upload_id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // external images embedded from the mapres are replaced with their content, the
+    // other peers may not have the same external images.
+    fn resolve_embeds(&self, room: &Room, req: &mut Request) -> Result<(), Error> {
+        let (name, image) = match req {
+            Request::Create(CreateReq::Image(name, image)) => (name.clone(), image),
+            Request::Edit(EditReq::Image(i, part)) => {
+                let name = match &part.name {
+                    Some(name) => name.clone(),
+                    None => {
+                        let map = room.map();
+                        let image = map.images.get(*i as usize).ok_or(Error::ImageNotFound)?;
+                        image.name().to_owned()
+                    }
+                };
+                match &mut part.data {
+                    Some(image) => (name, image),
+                    None => return Ok(()),
+                }
             }
+            _ => return Ok(()),
+        };
+
+        if let Image::External { embed: true, .. } = image {
+            let data = self
+                .mapres
+                .get(&name)
+                .ok_or(Error::NotFound("external image data"))?;
+            *image = Image::Embedded(Base64(encode_png(&data)?));
         }
+
+        Ok(())
     }
 
-    pub(crate) fn handle_request(&self, peer: &mut Peer, packet: RecvPacket) {
-        let resp = self.do_request(peer, packet.content.clone());
+    pub(crate) async fn handle_request(&self, peer: &mut Peer, mut packet: RecvPacket) {
+        let resp = match self.resolve_uploads(&mut packet.content) {
+            Ok(upload) => {
+                let resp = self.do_request(peer, packet.content.clone()).await;
+                // a failed request can be retried with the same upload.
+                if let (Ok(_), Some(upload_id)) = (&resp, upload) {
+                    self.uploads.remove(&upload_id);
+                }
+                resp
+            }
+            Err(e) => Err(e),
+        };
+        let ok = resp.is_ok();
+
+        if let (true, Some(room)) = (ok, &peer.room) {
+            if let Err(e) = self.resolve_embeds(room, &mut packet.content) {
+                log::error!("failed to resolve the embedded image of a request: {e}");
+            }
+        }
         self.do_respond(peer, &packet, resp);
+
+        // the automapped tiles are sent after the edit, so that the peers apply them last.
+        if let (true, Some(room), Request::Edit(EditReq::Tiles(g, l, tiles))) =
+            (ok, &peer.room, &packet.content)
+        {
+            if let Err(e) = self.live_automap(room, *g, *l, tiles.rect) {
+                log::debug!("live automap of layer {g}/{l} failed: {e}");
+            }
+        }
     }
 
     pub(crate) async fn handle_websocket(&self, socket: WebSocket, addr: SocketAddr) {
-        let (mut tx, ws_recv) = socket.split();
+        let (mut tx, mut ws_recv) = socket.split();
         let (ws_send, mut rx) = unbounded();
 
         let mut peer = Peer::new(addr, ws_send);
@@ -301,29 +535,29 @@ impl Server {
 
         let fut_send = rx.map(Ok).forward(tx);
 
-        let fut_recv = ws_recv.try_for_each(|msg| {
-            if let WebSocketMessage::Text(msg) = msg {
-                // log::debug!("text message received from {}: {}", addr, text);
-                match serde_json::from_str(&msg) {
-                    Ok(req) => {
-                        self.handle_request(&mut peer, req);
-                    }
-                    Err(e) => {
-                        log::error!("failed to parse message: {e} in {msg}");
-                        Server::send(
-                            &peer,
-                            None,
-                            Message::Response(Err(Error::BadRequest(e.to_string()))),
-                        )
-                    }
-                };
+        let fut_recv = async {
+            while let Some(Ok(msg)) = ws_recv.next().await {
+                if let WebSocketMessage::Text(msg) = msg {
+                    // log::debug!("text message received from {}: {}", addr, text);
+                    match serde_json::from_str(&msg) {
+                        Ok(req) => {
+                            self.handle_request(&mut peer, req).await;
+                        }
+                        Err(e) => {
+                            log::error!("failed to parse message: {e} in {msg}");
+                            Server::send(
+                                &peer,
+                                None,
+                                Message::Response(Err(Error::BadRequest(e.to_string()))),
+                            )
+                        }
+                    };
+                }
             }
-
-            futures::future::ok(())
-        });
+        };
 
         // wait for either sender or receiver to complete: this means the connection is closed.
-        futures::future::select(fut_send, fut_recv).await;
+        futures::future::select(fut_send, std::pin::pin!(fut_recv)).await;
 
         if let Some(room) = &peer.room {
             room.remove_peer(&peer);
@@ -346,6 +580,7 @@ impl Server {
             .map(|(k, v)| MapDetail {
                 name: k.to_owned(),
                 users: v.peer_count(),
+                thumbnail: format!("/maps/{k}/thumbnail"),
             })
             .collect()
     }
@@ -374,15 +609,19 @@ impl Server {
             return Err(Error::MaxMaps);
         }
 
-        let mut map = match creation.method {
+        let mut map = match &creation.method {
             CreationMethod::Upload(file) => {
                 if file.0.len() > self.max_map_size {
                     return Err(Error::MapTooBig);
                 }
                 twmap::TwMap::parse(&file.0).map_err(|e| Error::Map(e.to_string()))?
             }
+            CreationMethod::UploadId(id) => {
+                let file = self.uploads.data(id)?;
+                twmap::TwMap::parse(&file).map_err(|e| Error::Map(e.to_string()))?
+            }
             CreationMethod::Clone(clone_name) => {
-                let room = self.room(&clone_name)?;
+                let room = self.room(clone_name)?;
                 let map = room.map().clone();
                 map
             }
@@ -396,7 +635,8 @@ impl Server {
                 let mut group = twmap::Group::physics();
                 let layer = twmap::GameLayer {
                     tiles: twmap::CompressedData::Loaded(ndarray::Array2::default((
-                        h as usize, w as usize,
+                        *h as usize,
+                        *w as usize,
                     ))),
                 };
                 group.layers.push(twmap::Layer::Game(layer));
@@ -456,6 +696,10 @@ impl Server {
             }
         }
 
+        if let CreationMethod::UploadId(id) = &creation.method {
+            self.uploads.remove(id);
+        }
+
         log::info!("map created `{}`", map_name);
         Ok(())
     }
@@ -476,6 +720,107 @@ impl Server {
         Ok(())
     }
 
+    fn parse_map(&self, file: &[u8]) -> Result<twmap::TwMap, Error> {
+        if file.len() > self.max_map_size {
+            return Err(Error::MapTooBig);
+        }
+
+        let mut map = twmap::TwMap::parse(file).map_err(|e| Error::Map(e.to_string()))?;
+        map.load().map_err(|e| Error::Map(e.to_string()))?;
+        Ok(map)
+    }
+
+    /// Without a file, compares the saved map with the current state of the room.
+    /// With a file, compares the current state of the room with the file.
+    pub fn get_diff(&self, map_name: &str, file: Option<&[u8]>) -> Result<MapDiff, Error> {
+        let room = self.room(map_name)?;
+
+        let (base, other) = match file {
+            Some(file) => (room.map().clone(), self.parse_map(file)?), // cloned to avoid blocking
+            None => (
+                load_map(room.map_path()).map_err(|e| Error::Map(e.to_string()))?,
+                room.map().clone(),
+            ),
+        };
+
+        Ok(diff_maps(&base, &other))
+    }
+
+    pub fn get_stats(&self, map_name: &str) -> Result<MapStats, Error> {
+        let mut map = self.room(map_name)?.map().clone(); // cloned to avoid blocking
+        map_stats(&mut map, self.max_map_size)
+    }
+
+    pub fn diff_files(&self, base: &[u8], other: &[u8]) -> Result<MapDiff, Error> {
+        let base = self.parse_map(base)?;
+        let other = self.parse_map(other)?;
+        Ok(diff_maps(&base, &other))
+    }
+
+    pub fn optimize_map(&self, map_name: &str) -> Result<OptimizeReport, Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+        optimize_map(&mut map)
+    }
+
+    pub fn transform_map(&self, map_name: &str, transform: &MapTransform) -> Result<(), Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+        transform_map(&mut map, transform)
+    }
+
+    pub fn merge_files(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Result<MergedMap, Error> {
+        let base = self.parse_map(base)?;
+        let ours = self.parse_map(ours)?;
+        let theirs = self.parse_map(theirs)?;
+        let (mut map, conflicts) = merge_maps(&base, &ours, &theirs);
+
+        let mut buf = Vec::new();
+        map.save(&mut buf).map_err(|e| Error::Map(e.to_string()))?;
+
+        if buf.len() > self.max_map_size {
+            return Err(Error::MapTooBig);
+        }
+
+        Ok(MergedMap {
+            map: Base64(buf),
+            conflicts,
+        })
+    }
+
+    pub fn render_map(&self, map_name: &str, params: &RenderParams) -> Result<Vec<u8>, Error> {
+        let map = self.room(map_name)?.map().clone(); // cloned to avoid blocking
+        let viewport = Viewport::new(&map, params)?;
+        let external = self.mapres.external_images(&map);
+        let image = render_map(&map, &map_images(&map, &external), &viewport);
+        encode_png(&image)
+    }
+
+    pub fn get_thumbnail(&self, map_name: &str) -> Result<Vec<u8>, Error> {
+        let room = self.room(map_name)?;
+
+        if let Some(buf) = room.thumbnail().as_ref() {
+            return Ok(buf.clone());
+        }
+
+        // the saved map is rendered, so that listing maps does not load the rooms.
+        let map = load_map(room.map_path()).map_err(|e| Error::Map(e.to_string()))?;
+        // rendered larger and downscaled, to smooth out the tiles
+        let viewport = Viewport::fit(&map, 4 * THUMBNAIL_SIZE);
+        let external = self.mapres.external_images(&map);
+        let image = render_map(&map, &map_images(&map, &external), &viewport);
+        let image = image::imageops::resize(
+            &image,
+            (image.width() / 4).max(1),
+            (image.height() / 4).max(1),
+            image::imageops::FilterType::Triangle,
+        );
+
+        let buf = encode_png(&image)?;
+        *room.thumbnail() = Some(buf.clone());
+        Ok(buf)
+    }
+
     pub fn get_info(&self, map_name: &str) -> Result<twmap::Info, Error> {
         Ok(self.room(map_name)?.map().info.clone())
     }
@@ -521,9 +866,12 @@ impl Server {
             .clone(); // cloned to avoid blocking
 
         match image {
-            twmap::Image::External(_) => {
-                Err(Error::Internal("cannot send external images".into()))?
-            }
+            twmap::Image::External(image) => self
+                .mapres
+                .get(&image.name)
+                .ok_or(Error::NotFound("external image data"))?
+                .write_to(&mut std::io::Cursor::new(&mut buf), ImageFormat::Png)
+                .map_err(|e| Error::Internal(e.to_string().into()))?,
             twmap::Image::Embedded(image) => image
                 .image
                 .unwrap_ref()
@@ -534,9 +882,12 @@ impl Server {
         Ok(buf)
     }
 
-    pub fn put_image(&self, map_name: &str, image_name: &str, create: Image) -> Result<(), Error> {
-        let room = self.room(map_name)?;
-
+    fn create_image(
+        &self,
+        version: twmap::Version,
+        image_name: &str,
+        create: Image,
+    ) -> Result<twmap::Image, Error> {
         if image_name.len() > twmap::Image::MAX_NAME_LENGTH {
             return Err(Error::InvalidFileName);
         }
@@ -545,32 +896,184 @@ impl Server {
             return Err(Error::InvalidFileName);
         }
 
-        if room.map().images.len() == 64usize {
-            return Err(Error::MaxImages);
-        }
-
         let image = match create {
-            Image::External { size: _ } => {
+            Image::External { size: _, embed } => {
                 // this also checks is_external_name
-                let size = twmap::constants::external_dimensions(image_name, room.map().version)
+                let size = twmap::constants::external_dimensions(image_name, version)
                     .ok_or(Error::InvalidImage)?;
 
-                twmap::Image::External(twmap::ExternalImage {
+                let image = twmap::ExternalImage {
                     name: image_name.to_owned(),
                     size,
-                })
+                };
+
+                if embed {
+                    twmap::Image::Embedded(self.mapres.embed(&image)?)
+                } else {
+                    twmap::Image::External(image)
+                }
             }
-            Image::Embedded(file) => twmap::Image::Embedded(
-                twmap::EmbeddedImage::from_reader(image_name, std::io::Cursor::new(file.0))
+            Image::Embedded(Base64(file)) => twmap::Image::Embedded(
+                twmap::EmbeddedImage::from_reader(image_name, std::io::Cursor::new(file))
                     .map_err(|_| Error::InvalidImage)?,
             ),
+            Image::Upload { upload_id } => twmap::Image::Embedded(
+                twmap::EmbeddedImage::from_reader(
+                    image_name,
+                    std::io::Cursor::new(self.uploads.data(&upload_id)?),
+                )
+                .map_err(|_| Error::InvalidImage)?,
+            ),
+        };
+
+        Ok(image)
+    }
+
+    pub fn put_image(&self, map_name: &str, image_name: &str, create: Image) -> Result<(), Error> {
+        let room = self.room(map_name)?;
+
+        if room.map().images.len() >= MAX_IMAGES {
+            return Err(Error::MaxImages);
+        }
+
+        // an upload used here directly is removed once the image is added.
+        let upload_id = match &create {
+            Image::Upload { upload_id } => Some(upload_id.clone()),
+            _ => None,
         };
+        let version = room.map().version;
+        let image = self.create_image(version, image_name, create)?;
 
         image
             .check(&room.map(), &mut ())
             .map_err(|e| Error::Map(e.to_string()))?;
 
         room.map().images.push(image);
+
+        if let Some(id) = upload_id {
+            self.uploads.remove(&id);
+        }
+        Ok(())
+    }
+
+    pub fn edit_image(
+        &self,
+        map_name: &str,
+        image_index: u16,
+        part_image: PartialImage,
+    ) -> Result<(), Error> {
+        let room = self.room(map_name)?;
+
+        let (version, image) = {
+            let map = room.map();
+            let image = map
+                .images
+                .get(image_index as usize)
+                .ok_or(Error::ImageNotFound)?;
+            (map.version, image.clone())
+        };
+
+        let image_name = part_image.name.unwrap_or_else(|| image.name().to_owned());
+        let upload_id = match &part_image.data {
+            Some(Image::Upload { upload_id }) => Some(upload_id.clone()),
+            _ => None,
+        };
+
+        let image = match (part_image.data, image) {
+            (Some(create), _) => self.create_image(version, &image_name, create)?,
+            // the name of an external image determines its content.
+            (None, twmap::Image::External(image)) => self.create_image(
+                version,
+                &image_name,
+                Image::External {
+                    size: image.size,
+                    embed: false,
+                },
+            )?,
+            (None, twmap::Image::Embedded(mut image)) => {
+                if image_name.len() > twmap::Image::MAX_NAME_LENGTH || !check_file_name(&image_name)
+                {
+                    return Err(Error::InvalidFileName);
+                }
+                image.name = image_name;
+                twmap::Image::Embedded(image)
+            }
+        };
+
+        let mut map = room.map();
+
+        image
+            .check(&map, &mut ())
+            .map_err(|e| Error::Map(e.to_string()))?;
+
+        // tiles layers using the image must still get a valid tileset.
+        let used_by_tilemap = map.groups.iter().flat_map(|group| group.layers.iter()).any(
+            |layer| matches!(layer, twmap::Layer::Tiles(layer) if layer.image == Some(image_index)),
+        );
+
+        if used_by_tilemap {
+            self.check_tileset(&image)?;
+        }
+
+        *map.images
+            .get_mut(image_index as usize)
+            .ok_or(Error::ImageNotFound)? = image;
+
+        if let Some(id) = upload_id {
+            self.uploads.remove(&id);
+        }
+        Ok(())
+    }
+
+    pub fn embed_image(&self, map_name: &str, image_index: u16) -> Result<(), Error> {
+        let room = self.room(map_name)?;
+
+        let image = match room.map().images.get(image_index as usize) {
+            Some(twmap::Image::External(image)) => image.clone(),
+            Some(twmap::Image::Embedded(_)) => return Err(Error::ImageAlreadyEmbedded),
+            None => return Err(Error::ImageNotFound),
+        };
+
+        let embedded = self.mapres.embed(&image)?;
+
+        // the map was unlocked while reading the mapres, the image may have been
+        // replaced or deleted in the meantime.
+        let mut map = room.map();
+        match map.images.get_mut(image_index as usize) {
+            Some(slot @ twmap::Image::External(_)) if *slot.name() == image.name => {
+                *slot = twmap::Image::Embedded(embedded);
+                Ok(())
+            }
+            Some(twmap::Image::Embedded(_)) => Err(Error::ImageAlreadyEmbedded),
+            _ => Err(Error::ImageNotFound),
+        }
+    }
+
+    pub fn unembed_image(&self, map_name: &str, image_index: u16) -> Result<(), Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+
+        let image = match map.images.get(image_index as usize) {
+            Some(twmap::Image::Embedded(image)) => image,
+            Some(twmap::Image::External(_)) => return Err(Error::ImageAlreadyExternal),
+            None => return Err(Error::ImageNotFound),
+        };
+
+        // the clients would display a different image if the content does not match.
+        let size = twmap::constants::external_dimensions(&image.name, map.version)
+            .ok_or(Error::ImageNotExternal)?;
+        let external = self
+            .mapres
+            .get(&image.name)
+            .ok_or(Error::NotFound("external image data"))?;
+        if *external != *image.image.unwrap_ref() {
+            return Err(Error::ImageNotExternal);
+        }
+
+        map.images[image_index as usize] = twmap::Image::External(twmap::ExternalImage {
+            name: image.name.clone(),
+            size,
+        });
         Ok(())
     }
 
@@ -612,6 +1115,22 @@ impl Server {
             .to_owned())
     }
 
+    pub fn eval_envelope(
+        &self,
+        map_name: &str,
+        env_index: u16,
+        params: &EnvelopeEval,
+    ) -> Result<Vec<EnvSample>, Error> {
+        let env = self.get_envelope(map_name, env_index)?;
+        eval_envelope(&env, params)
+    }
+
+    pub fn get_envelope_usage(&self, map_name: &str) -> Result<Vec<EnvelopeUsage>, Error> {
+        let room = self.room(map_name)?;
+        let map = room.map();
+        Ok(envelopes_usage(&map))
+    }
+
     pub fn put_envelope(&self, map_name: &str, part_env: PartialEnvelope) -> Result<(), Error> {
         let room = self.room(map_name)?;
 
@@ -681,7 +1200,13 @@ impl Server {
         Ok(())
     }
 
-    pub fn delete_envelope(&self, map_name: &str, env_index: u16) -> Result<(), Error> {
+    /// With `force`, the layers, quads and sound sources using the envelope are left without envelope.
+    pub fn delete_envelope(
+        &self,
+        map_name: &str,
+        env_index: u16,
+        force: bool,
+    ) -> Result<(), Error> {
         let room = self.room(map_name)?;
         let mut map = room.map();
 
@@ -689,12 +1214,16 @@ impl Server {
             return Err(Error::EnvelopeNotFound);
         }
 
-        if map.is_env_in_use(env_index) {
+        if !force && map.is_env_in_use(env_index) {
             return Err(Error::EnvelopeInUse);
         }
 
         map.envelopes.remove(env_index as usize);
-        map.edit_env_indices(|i| i.map(|i| if i > env_index { i - 1 } else { i }));
+        map.edit_env_indices(|i| match i {
+            Some(i) if i == env_index => None,
+            Some(i) if i > env_index => Some(i - 1),
+            i => i,
+        });
 
         Ok(())
     }
@@ -744,6 +1273,73 @@ impl Server {
         Ok(())
     }
 
+    /// Copies from another map into this one. The source map is not locked at the same time.
+    pub fn copy(&self, map_name: &str, req: &CopyReq) -> Result<CopyReport, Error> {
+        let fragment = {
+            let (CopyReq::Group(src, _)
+            | CopyReq::Layer(src, _, _)
+            | CopyReq::Image(src, _)
+            | CopyReq::Envelope(src, _)) = req;
+            let room = self.room(src)?;
+            let src_map = room.map();
+            match *req {
+                CopyReq::Group(_, g) => Fragment::group(&src_map, g),
+                CopyReq::Layer(_, (g, l), tgt) => Fragment::layer(&src_map, g, l, tgt),
+                CopyReq::Image(_, i) => Fragment::image(&src_map, i),
+                CopyReq::Envelope(_, e) => Fragment::envelope(&src_map, e),
+            }?
+        };
+
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+        let report = fragment.paste(&mut map)?;
+
+        match copy_requests(&map, req, &report) {
+            Ok(reqs) => self.broadcast_requests(&room, reqs),
+            Err(e) => {
+                log::error!("failed to send the copy to the peers: {e}");
+                self.broadcast_to_room(&room, Message::Broadcast(Broadcast::Reload));
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// The copy is inserted after the group, returns its index.
+    pub fn duplicate_group(&self, map_name: &str, group_index: u16) -> Result<u16, Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+
+        if map.groups.len() == u16::MAX as usize {
+            return Err(Error::MaxGroups);
+        }
+
+        let layers_count = map.groups.iter().flat_map(|g| g.layers.iter()).count();
+        let group = map
+            .groups
+            .get(group_index as usize)
+            .ok_or(Error::GroupNotFound)?;
+
+        if group.is_physics_group() {
+            return Err(Error::DuplicatePhysicsGroup);
+        }
+        group.layers.iter().try_for_each(check_creatable)?;
+        if layers_count + group.layers.len() > u16::MAX as usize {
+            return Err(Error::MaxLayers);
+        }
+
+        // the peers create the copy at the end of the map and move it in place.
+        let end = map.groups.len() as u16;
+        let reqs = create_group_requests(end, group)
+            .into_iter()
+            .chain(move_group_request(end, group_index + 1));
+        self.broadcast_requests(&room, reqs);
+
+        let group = group.clone();
+        map.groups.insert(group_index as usize + 1, group);
+        Ok(group_index + 1)
+    }
+
     pub fn edit_group(
         &self,
         map_name: &str,
@@ -844,6 +1440,43 @@ impl Server {
         })
     }
 
+    // the dimensions of external images are only known for the standard mapres, check
+    // the actual image when it is available.
+    fn check_tiles_image(
+        &self,
+        map: &twmap::TwMap,
+        part_layer: &PartialLayer,
+    ) -> Result<(), Error> {
+        if let PartialLayer::Tiles(PartialTilesLayer {
+            image: Some(Some(index)),
+            ..
+        }) = part_layer
+        {
+            if let Some(image) = map.images.get(*index as usize) {
+                self.check_tileset(image)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_tileset(&self, image: &twmap::Image) -> Result<(), Error> {
+        if !image.for_tilemap() {
+            return Err(Error::WrongTilesImage);
+        }
+
+        // the actual external image may differ from the known dimensions.
+        if let twmap::Image::External(image) = image {
+            if let Some(data) = self.mapres.get(&image.name) {
+                if data.width() % 16 != 0 || data.height() % 16 != 0 {
+                    return Err(Error::WrongTilesImage);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn put_layer(
         &self,
         map_name: &str,
@@ -853,6 +1486,7 @@ impl Server {
         part_layer.check_self()?;
         let room = self.room(map_name)?;
         part_layer.check_map(&room.map())?;
+        self.check_tiles_image(&room.map(), &part_layer)?;
 
         let mut map = room.map();
 
@@ -918,6 +1552,52 @@ impl Server {
         Ok(())
     }
 
+    /// The copy is inserted after the layer in the same group, returns its index.
+    pub fn duplicate_layer(
+        &self,
+        map_name: &str,
+        group_index: u16,
+        layer_index: u16,
+    ) -> Result<u16, Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+
+        let layers_count = map.groups.iter().flat_map(|g| g.layers.iter()).count();
+
+        if layers_count == u16::MAX as usize {
+            return Err(Error::MaxLayers);
+        }
+
+        let group = map
+            .groups
+            .get_mut(group_index as usize)
+            .ok_or(Error::GroupNotFound)?;
+        let layer = group
+            .layers
+            .get(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        match layer.kind() {
+            twmap::LayerKind::Game => return Err(Error::CreateGameLayer),
+            kind if kind.is_physics_layer() => return Err(Error::CreateDuplicatePhysicsLayer),
+            _ => check_creatable(layer)?,
+        }
+
+        // the peers create the copy at the end of the group and move it in place.
+        let end = group.layers.len() as u16;
+        let reqs = create_layer_requests(group_index, end, layer)
+            .into_iter()
+            .chain(move_layer_request(
+                (group_index, end),
+                (group_index, layer_index + 1),
+            ));
+        self.broadcast_requests(&room, reqs);
+
+        let layer = layer.clone();
+        group.layers.insert(layer_index as usize + 1, layer);
+        Ok(layer_index + 1)
+    }
+
     pub fn edit_layer(
         &self,
         map_name: &str,
@@ -928,6 +1608,7 @@ impl Server {
         part_layer.check_self()?;
         let room = self.room(map_name)?;
         part_layer.check_map(&room.map())?;
+        self.check_tiles_image(&room.map(), &part_layer)?;
 
         // edit
         {
@@ -1135,6 +1816,31 @@ impl Server {
         Ok(())
     }
 
+    pub fn find_tiles(&self, map_name: &str, query: &TilesQuery) -> Result<TilesFound, Error> {
+        let room = self.room(map_name)?;
+        let map = room.map();
+        find_tiles(&map, query)
+    }
+
+    pub fn replace_tiles(
+        &self,
+        map_name: &str,
+        query: &TilesQuery,
+        replacement: &TilesReplacement,
+    ) -> Result<usize, Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+        let replaced = replace_tiles(&mut map, query, replacement)?;
+
+        let reqs = replaced.iter().filter_map(|r| {
+            let layer = &map.groups[r.group as usize].layers[r.layer as usize];
+            layer_tiles_request(r.group, r.layer, layer, r.rect)
+        });
+        self.broadcast_requests(&room, reqs);
+
+        Ok(replaced.iter().map(|r| r.count).sum())
+    }
+
     pub fn get_quad(
         &self,
         map_name: &str,
@@ -1163,6 +1869,46 @@ impl Server {
         }
     }
 
+    /// The copies are added on top of the layer, in the order of `quads`. Returns their indices.
+    pub fn duplicate_quads(
+        &self,
+        map_name: &str,
+        group_index: u16,
+        layer_index: u16,
+        quads: &[u16],
+    ) -> Result<Vec<u16>, Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+        let layer = map
+            .groups
+            .get_mut(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get_mut(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        let twmap::Layer::Quads(layer) = layer else {
+            return Err(Error::WrongLayerType);
+        };
+
+        let copies = quads
+            .iter()
+            .map(|&q| layer.quads.get(q as usize).cloned())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::QuadNotFound)?;
+
+        if layer.quads.len() + copies.len() > u16::MAX as usize {
+            return Err(Error::MaxQuads);
+        }
+
+        let reqs = create_quads_requests(group_index, layer_index, &copies);
+        self.broadcast_requests(&room, reqs);
+
+        let start = layer.quads.len() as u16;
+        layer.quads.extend(copies);
+        Ok((start..layer.quads.len() as u16).collect())
+    }
+
     pub fn put_quad(
         &self,
         map_name: &str,
@@ -1227,6 +1973,37 @@ impl Server {
         }
     }
 
+    /// Applies the operation to many quads of the layer at once.
+    pub fn edit_quads(
+        &self,
+        map_name: &str,
+        group_index: u16,
+        layer_index: u16,
+        quads: &[u16],
+        op: &QuadsOp,
+    ) -> Result<(), Error> {
+        op.check_self()?;
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+        op.check_map(&map)?;
+        let layer = map
+            .groups
+            .get_mut(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get_mut(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        let twmap::Layer::Quads(layer) = layer else {
+            return Err(Error::WrongLayerType);
+        };
+
+        edit_quads(&mut layer.quads, quads, op)?;
+        let reqs = edit_quads_requests(group_index, layer_index, &layer.quads, quads, op);
+        self.broadcast_requests(&room, reqs);
+        Ok(())
+    }
+
     pub fn delete_quad(
         &self,
         map_name: &str,
@@ -1273,15 +2050,21 @@ impl Server {
                                 .unwrap_or_default()
                                 .to_string_lossy()
                                 .into_owned();
-                            let configs = (kind == AutomapperKind::DDNet)
-                                .then(|| {
-                                    let file = std::fs::read_to_string(&path).ok()?;
-                                    let am =
-                                        twmap::automapper::Automapper::parse(image.clone(), &file)
-                                            .ok()?;
-                                    Some(am.configs.iter().map(|c| c.name.to_owned()).collect())
-                                })
-                                .flatten();
+                            let configs = match kind {
+                                AutomapperKind::DDNet => {
+                                    std::fs::read_to_string(&path).ok().map(|file| {
+                                        let (am, _) = parse_rules(image.clone(), &file);
+                                        am.configs.iter().map(|c| c.name.to_owned()).collect()
+                                    })
+                                }
+                                AutomapperKind::Teeworlds => {
+                                    std::fs::read_to_string(&path).ok().map(|file| {
+                                        let (am, _) = parse_tw_automapper(&file);
+                                        am.configs.into_iter().map(|c| c.name).collect()
+                                    })
+                                }
+                                AutomapperKind::RulesPP => None,
+                            };
 
                             Some(AutomapperDetail {
                                 name,
@@ -1323,65 +2106,7 @@ impl Server {
         Ok(file)
     }
 
-    pub fn compile_rpp(&self, path: &Path) -> Result<(), Error> {
-        let rpp_path = self
-            .rpp_path
-            .as_ref()
-            .ok_or(Error::Internal("rpp path not provided".into()))?;
-
-        let root = path.parent().ok_or(Error::Internal("no parent".into()))?;
-
-        let in_fname = path
-            .file_name()
-            .ok_or(Error::Internal("no file name".into()))?
-            .to_string_lossy();
-        let out_fname = format!(
-            "{}.rules",
-            path.file_stem()
-                .ok_or(Error::Internal("no file name".into()))?
-                .to_string_lossy()
-        );
-
-        let rpp_exe = rpp_path.join("rpp");
-        let rpp_base_r = rpp_path.join("base.r");
-        let rpp_base_p = rpp_path.join("base.p");
-
-        let mut cmd = std::process::Command::new(&rpp_exe);
-        cmd.current_dir(root).args([
-            "--output",
-            &out_fname,
-            "--memory",
-            "100",
-            "--include",
-            &rpp_base_r.to_string_lossy(),
-            "--include",
-            &rpp_base_p.to_string_lossy(),
-            "--no-pause",
-            &in_fname,
-        ]);
-
-        log::debug!("rpp: {cmd:?}");
-
-        let exec = cmd
-            .output()
-            .map_err(|e| Error::Internal(e.to_string().into()))?;
-
-        match exec.status.code() {
-            Some(0) => Ok(()),
-            Some(_) => {
-                log::info!("rpp: {}", String::from_utf8_lossy(&exec.stderr));
-                Err(Error::Automapper(
-                    String::from_utf8_lossy(&exec.stderr).into_owned(),
-                ))
-            }
-            None => {
-                log::error!("rpp: {}", String::from_utf8_lossy(&exec.stderr));
-                Err(Error::Internal("rpp: no exit status".into()))
-            }
-        }
-    }
-
-    pub fn put_automapper(
+    pub async fn put_automapper(
         &self,
         map_name: &str,
         am: &str,
@@ -1405,8 +2130,36 @@ impl Server {
         std::fs::create_dir_all(room.automapper_path().unwrap()).ok();
         std::fs::write(&path, file).map_err(|e| Error::Internal(e.to_string().into()))?;
 
+        if kind == AutomapperKind::DDNet {
+            let (_, diagnostics) = parse_rules(am.to_owned(), file);
+            return Ok(diagnostics);
+        }
+
+        if kind == AutomapperKind::Teeworlds {
+            let (_, diagnostics) = parse_tw_automapper(file);
+            return Ok(diagnostics);
+        }
+
         if kind == AutomapperKind::RulesPP {
-            match self.compile_rpp(&path) {
+            // Rules++ is compiled by the external rpp, there is no compiler built in the server.
+            let file_diagnostic = |msg: String, severity| AutomapperDiagnostic {
+                span: Span {
+                    line_start: 1,
+                    col_start: 1,
+                    line_end: 1,
+                    col_end: 1,
+                },
+                msg,
+                severity,
+            };
+            let Some(rpp) = &self.rpp else {
+                return Ok(vec![file_diagnostic(
+                    "Rules++ is not enabled on this server (started without --rpp), the file is saved but not compiled".to_owned(),
+                    Severity::Warning,
+                )]);
+            };
+
+            match rpp.compile(&path).await {
                 Ok(()) => {
                     let target = path.with_extension("rules");
                     let file = std::fs::read_to_string(&target)
@@ -1421,31 +2174,49 @@ impl Server {
                     self.broadcast_to_room(&room, message);
                 }
                 Err(Error::Automapper(s)) => {
-                    let reg = Regex::new(r"\[(\d+):(\d+)-(\d+):(\d+)\]\s*(.+)").unwrap();
-                    let diagnostics = s
-                        .split('\n')
-                        .filter_map(|s| {
-                            let caps = reg.captures(s)?;
-                            Some(AutomapperDiagnostic {
-                                span: Span {
-                                    line_start: caps[1].parse().ok()?,
-                                    col_start: caps[2].parse().ok()?,
-                                    line_end: caps[3].parse().ok()?,
-                                    col_end: caps[4].parse().ok()?,
-                                },
-                                msg: caps[5].to_string(),
-                            })
-                        })
-                        .collect();
+                    let diagnostics = rpp_diagnostics(&s);
+                    if diagnostics.is_empty() {
+                        return Ok(vec![file_diagnostic(s, Severity::Error)]);
+                    }
                     return Ok(diagnostics);
                 }
-                Err(_) => {}
+                Err(e) => {
+                    return Ok(vec![file_diagnostic(
+                        format!("Rules++ compilation failed: {e}"),
+                        Severity::Error,
+                    )]);
+                }
             }
         }
 
         Ok(vec![])
     }
 
+    pub async fn import_automapper(
+        &self,
+        map_name: &str,
+        am: &str,
+        version: Option<u32>,
+    ) -> Result<ImportedAutomapper, Error> {
+        let (version, file) = self.library.get(am, version)?;
+        // published automappers have no errors, but may not compile on this server.
+        let diagnostics = self.put_automapper(map_name, am, &file).await?;
+
+        let room = self.room(map_name)?;
+        let message = Message::Request(Request::Create(CreateReq::Automapper(am.to_owned(), file)));
+        self.broadcast_to_room(&room, message);
+        Ok(ImportedAutomapper {
+            version,
+            diagnostics,
+        })
+    }
+
+    /// Returns the published version.
+    pub async fn publish_automapper(&self, map_name: &str, am: &str) -> Result<u32, Error> {
+        let file = self.get_automapper(map_name, am)?;
+        self.library.publish(am, &file, self.rpp.as_ref()).await
+    }
+
     pub fn delete_automapper(&self, map_name: &str, am: &str) -> Result<(), Error> {
         if !check_file_name(am) {
             return Err(Error::InvalidFileName);
@@ -1466,6 +2237,46 @@ impl Server {
         Ok(())
     }
 
+    // the automapper of the image of a tiles layer, the DDNet automapper of the image is
+    // preferred to the teeworlds one.
+    fn layer_automapper(
+        &self,
+        room: &Room,
+        map: &twmap::TwMap,
+        group_index: u16,
+        layer_index: u16,
+    ) -> Result<LayerAutomapper, Error> {
+        let layer = map
+            .groups
+            .get(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        let image_name = if let twmap::Layer::Tiles(layer) = layer {
+            let index = layer.image.ok_or(Error::LayerHasNoImage)?;
+            map.images
+                .get(index as usize)
+                .ok_or(Error::ImageNotFound)?
+                .name()
+                .to_owned()
+        } else {
+            return Err(Error::WrongLayerType);
+        };
+
+        let am_dir = room.automapper_path().ok_or(Error::AutomapperNotFound)?;
+        let am_path = am_dir.join(format!("{image_name}.rules"));
+        if am_path.is_file() {
+            let file = std::fs::read_to_string(am_path).map_err(|_| Error::AutomapperNotFound)?;
+            Ok(LayerAutomapper::DDNet(load_rules(image_name, &file)?))
+        } else {
+            let am_path = am_dir.join(format!("{image_name}.json"));
+            let file = std::fs::read_to_string(am_path).map_err(|_| Error::AutomapperNotFound)?;
+            Ok(LayerAutomapper::Teeworlds(load_tw_automapper(&file)?))
+        }
+    }
+
     pub fn apply_automapper(
         &self,
         map_name: &str,
@@ -1475,27 +2286,164 @@ impl Server {
         let room = self.room(map_name)?;
         let mut map = room.map();
 
-        let image_name = {
-            let layer = map
-                .groups
-                .get(group_index as usize)
-                .ok_or(Error::GroupNotFound)?
-                .layers
-                .get(layer_index as usize)
-                .ok_or(Error::LayerNotFound)?;
+        let automapper = self.layer_automapper(&room, &map, group_index, layer_index)?;
 
-            if let twmap::Layer::Tiles(layer) = layer {
-                let index = layer.image.ok_or(Error::LayerHasNoImage)?;
-                map.images
-                    .get(index as usize)
-                    .ok_or(Error::ImageNotFound)?
-                    .name()
-                    .to_owned()
-            } else {
-                return Err(Error::WrongLayerType);
+        let layer = map
+            .groups
+            .get_mut(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get_mut(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        if let twmap::Layer::Tiles(layer) = layer {
+            automapper.run(&layer.automapper_config, layer.tiles.unwrap_mut())
+        } else {
+            Err(Error::WrongLayerType)
+        }
+    }
+
+    /// Runs an automapper source on a copy of the layer, the room is not changed.
+    pub async fn preview_automapper(
+        &self,
+        map_name: &str,
+        group_index: u16,
+        layer_index: u16,
+        preview: &AutomapPreview,
+    ) -> Result<AutomapDiff, Error> {
+        if !check_file_name(&preview.name) {
+            return Err(Error::InvalidFileName);
+        }
+        let path = Path::new(&preview.name);
+        let kind = is_automapper(path).ok_or(Error::InvalidFileName)?;
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        let automapper = match kind {
+            AutomapperKind::DDNet => LayerAutomapper::DDNet(load_rules(name, &preview.file)?),
+            AutomapperKind::Teeworlds => {
+                LayerAutomapper::Teeworlds(load_tw_automapper(&preview.file)?)
+            }
+            AutomapperKind::RulesPP => {
+                let rpp = self.rpp.as_ref().ok_or(Error::Automapper(
+                    "Rules++ is not enabled on this server".to_owned(),
+                ))?;
+                let rules = rpp.compile_source(&name, &preview.file).await?;
+                LayerAutomapper::DDNet(load_rules(name, &rules)?)
             }
         };
 
+        let room = self.room(map_name)?;
+        let map = room.map();
+
+        let layer = map
+            .groups
+            .get(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        let (config, mut tiles) = if let twmap::Layer::Tiles(layer) = layer {
+            (
+                layer.automapper_config.clone(),
+                layer.tiles.unwrap_ref().clone(),
+            )
+        } else {
+            return Err(Error::WrongLayerType);
+        };
+        drop(map);
+
+        automapper.run_region(&config, &preview.region, &mut tiles)
+    }
+
+    // reruns the automapper of a tiles layer set to automatic around the edited tiles,
+    // and sends the tiles that changed to all the peers.
+    fn live_automap(
+        &self,
+        room: &Room,
+        group_index: u16,
+        layer_index: u16,
+        rect: vek::Rect<u32, u32>,
+    ) -> Result<(), Error> {
+        let mut map = room.map();
+
+        let automatic = match map
+            .groups
+            .get(group_index as usize)
+            .and_then(|g| g.layers.get(layer_index as usize))
+        {
+            Some(twmap::Layer::Tiles(layer)) => {
+                layer.automapper_config.automatic && layer.automapper_config.config.is_some()
+            }
+            _ => false,
+        };
+        if !automatic {
+            return Ok(());
+        }
+
+        let automapper = self.layer_automapper(room, &map, group_index, layer_index)?;
+
+        let Some(twmap::Layer::Tiles(layer)) = map
+            .groups
+            .get_mut(group_index as usize)
+            .and_then(|g| g.layers.get_mut(layer_index as usize))
+        else {
+            return Err(Error::WrongLayerType);
+        };
+
+        let tiles = layer.tiles.unwrap_mut();
+        let (h, w) = tiles.dim();
+        // the tiles changed in place can change the whole layer.
+        let margin = automapper
+            .reach(&layer.automapper_config)
+            .unwrap_or(u32::MAX);
+        let x = rect.x.saturating_sub(margin);
+        let y = rect.y.saturating_sub(margin);
+        let x_end = (rect.x + rect.w).saturating_add(margin).min(w as u32);
+        let y_end = (rect.y + rect.h).saturating_add(margin).min(h as u32);
+        let region = AutomapRegion {
+            rect: Some(vek::Rect::new(x, y, x_end - x, y_end - y)),
+            ..Default::default()
+        };
+
+        let diff = automapper.run_region(&layer.automapper_config, &region, tiles)?;
+        if diff.changes.is_empty() {
+            return Ok(());
+        }
+
+        // only the bounding box of the changed tiles is sent.
+        let x = diff.changes.iter().map(|c| c.x).min().unwrap();
+        let y = diff.changes.iter().map(|c| c.y).min().unwrap();
+        let x_end = diff.changes.iter().map(|c| c.x).max().unwrap() + 1;
+        let y_end = diff.changes.iter().map(|c| c.y).max().unwrap() + 1;
+        let tiles = tiles.slice(ndarray::s![
+            y as usize..y_end as usize,
+            x as usize..x_end as usize
+        ]);
+        let message = Message::Request(tiles_request(group_index, layer_index, x, y, tiles));
+        drop(map);
+
+        self.broadcast_to_room(room, message);
+
+        Ok(())
+    }
+
+    pub fn automap_region(
+        &self,
+        map_name: &str,
+        group_index: u16,
+        layer_index: u16,
+        region: &AutomapRegion,
+    ) -> Result<AutomapDiff, Error> {
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+
+        let automapper = self.layer_automapper(&room, &map, group_index, layer_index)?;
+
         let layer = map
             .groups
             .get_mut(group_index as usize)
@@ -1505,22 +2453,80 @@ impl Server {
             .ok_or(Error::LayerNotFound)?;
 
         if let twmap::Layer::Tiles(layer) = layer {
-            let am_path = room
-                .automapper_path()
-                .ok_or(Error::AutomapperNotFound)?
-                .join(format!("{image_name}.rules"));
-            let file = std::fs::read_to_string(am_path).map_err(|_| Error::AutomapperNotFound)?;
-            let automapper = twmap::automapper::Automapper::parse(image_name, &file)
-                .map_err(|e| Error::Automapper(e.to_string()))?;
-            layer
-                .run_automapper(&automapper)
-                .map_err(|_| Error::Automapper("config out of bounds".to_owned()))?;
-            Ok(())
+            automapper.run_region(&layer.automapper_config, region, layer.tiles.unwrap_mut())
         } else {
             Err(Error::WrongLayerType)
         }
     }
 
+    fn load_rules(&self, map_name: &str, am: &str) -> Result<twmap::automapper::Automapper, Error> {
+        if is_automapper(Path::new(am)) != Some(AutomapperKind::DDNet) {
+            return Err(Error::Automapper(format!(
+                "{am} is not a .rules automapper"
+            )));
+        }
+        let file = self.get_automapper(map_name, am)?;
+        load_rules(am.trim_end_matches(".rules").to_owned(), &file)
+    }
+
+    pub fn remap_tiles(
+        &self,
+        map_name: &str,
+        group_index: u16,
+        layer_index: u16,
+        remap: &RemapTiles,
+    ) -> Result<RemapReport, Error> {
+        let tile_map = match &remap.remapping {
+            Remapping::Table { entries } => TileMap::from_entries(entries),
+            Remapping::Automappers { from, to } => TileMap::from_automappers(
+                &self.load_rules(map_name, from)?,
+                &self.load_rules(map_name, to)?,
+            ),
+        };
+
+        let room = self.room(map_name)?;
+        let mut map = room.map();
+
+        if let Some(index) = remap.image {
+            let img = map.images.get(index as usize).ok_or(Error::ImageNotFound)?;
+            self.check_tileset(img)?;
+        }
+
+        let layer = map
+            .groups
+            .get_mut(group_index as usize)
+            .ok_or(Error::GroupNotFound)?
+            .layers
+            .get_mut(layer_index as usize)
+            .ok_or(Error::LayerNotFound)?;
+
+        let twmap::Layer::Tiles(layer) = layer else {
+            return Err(Error::WrongLayerType);
+        };
+
+        let report = tile_map.apply(layer.tiles.unwrap_mut());
+        let mut reqs = Vec::new();
+        if report.remapped != 0 {
+            let tiles = layer.tiles.unwrap_ref().view();
+            reqs.push(tiles_request(group_index, layer_index, 0, 0, tiles));
+        }
+        if let Some(index) = remap.image {
+            layer.image = Some(index);
+            let part = PartialTilesLayer {
+                image: Some(Some(index)),
+                ..Default::default()
+            };
+            reqs.push(Request::Edit(EditReq::Layer(
+                group_index,
+                layer_index,
+                Box::new(PartialLayer::Tiles(part)),
+            )));
+        }
+        self.broadcast_requests(&room, reqs);
+
+        Ok(report)
+    }
+
     pub fn move_image(&self, map_name: &str, src: u16, tgt: u16) -> Result<(), Error> {
         let room = self.room(map_name)?;
         let mut map = room.map();
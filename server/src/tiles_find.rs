@@ -0,0 +1,308 @@
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use twmap::{AnyTile, GameTile, Layer, Speedup, Switch, Tele, Tile, TileFlags, Tune, TwMap};
+use vek::Rect;
+
+use crate::error::Error;
+
+// Search and replacement of tiles in the tilemap layers of a map.
+
+/// Maximum number of positions returned by a search, the count is still exact.
+pub const MAX_FOUND_TILES: usize = 100_000;
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TilesQuery {
+    pub id: u8,
+    /// Only for layers whose tiles have flags. The opaque flag is ignored.
+    pub flags: Option<u8>,
+    /// Only for tele, switch and tune layers.
+    pub number: Option<u8>,
+    /// (group, layer) of the layers to search, all tilemap layers if not given.
+    pub layers: Option<Vec<(u16, u16)>>,
+    /// Region to search in, in tiles.
+    pub rect: Option<Rect<u32, u32>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayerTiles {
+    pub group: u16,
+    pub layer: u16,
+    pub count: usize,
+    /// (x, y) of the tiles found.
+    pub positions: Vec<(u32, u32)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TilesFound {
+    pub layers: Vec<LayerTiles>,
+    /// Some positions were left out because there are more than `MAX_FOUND_TILES`.
+    pub truncated: bool,
+}
+
+/// The matching tiles get the new id, and the flags and number if given.
+/// Replacing with id 0 clears the tiles.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TilesReplacement {
+    pub id: u8,
+    pub flags: Option<u8>,
+    pub number: Option<u8>,
+}
+
+/// Tiles replaced in a layer.
+#[derive(Clone, Debug)]
+pub struct LayerReplaced {
+    pub group: u16,
+    pub layer: u16,
+    pub count: usize,
+    /// Bounding box of the replaced tiles.
+    pub rect: Rect<u32, u32>,
+}
+
+trait TileNumber {
+    fn number(&self) -> Option<u8> {
+        None
+    }
+
+    fn number_mut(&mut self) -> Option<&mut u8> {
+        None
+    }
+}
+
+impl TileNumber for Tile {}
+impl TileNumber for GameTile {}
+impl TileNumber for Speedup {}
+
+macro_rules! impl_tile_number {
+    ($($tile:ty),*) => {
+        $(
+            impl TileNumber for $tile {
+                fn number(&self) -> Option<u8> {
+                    Some(self.number)
+                }
+
+                fn number_mut(&mut self) -> Option<&mut u8> {
+                    Some(&mut self.number)
+                }
+            }
+        )*
+    };
+}
+
+impl_tile_number!(Tele, Switch, Tune);
+
+impl TilesQuery {
+    fn matches<T: AnyTile + TileNumber>(&self, tile: &T) -> bool {
+        let flags_match = match (self.flags, tile.flags()) {
+            (Some(flags), Some(tile_flags)) => {
+                (tile_flags - TileFlags::OPAQUE).bits() == flags & !TileFlags::OPAQUE.bits()
+            }
+            _ => true,
+        };
+        let number_match = match (self.number, tile.number()) {
+            (Some(number), Some(tile_number)) => number == tile_number,
+            _ => true,
+        };
+        tile.id() == self.id && flags_match && number_match
+    }
+
+    fn region(&self, (h, w): (usize, usize)) -> (usize, usize, usize, usize) {
+        match self.rect {
+            Some(rect) => {
+                let x = (rect.x as usize).min(w);
+                let y = (rect.y as usize).min(h);
+                let x_end = (rect.x as usize).saturating_add(rect.w as usize).min(w);
+                let y_end = (rect.y as usize).saturating_add(rect.h as usize).min(h);
+                (x, y, x_end, y_end)
+            }
+            None => (0, 0, w, h),
+        }
+    }
+
+    fn selects(&self, group: u16, layer: u16) -> bool {
+        match &self.layers {
+            Some(layers) => layers.contains(&(group, layer)),
+            None => true,
+        }
+    }
+
+    fn check_layers(&self, map: &TwMap) -> Result<(), Error> {
+        for &(g, l) in self.layers.iter().flatten() {
+            let layer = map
+                .groups
+                .get(g as usize)
+                .ok_or(Error::GroupNotFound)?
+                .layers
+                .get(l as usize)
+                .ok_or(Error::LayerNotFound)?;
+            if layer.shape().is_none() {
+                return Err(Error::WrongLayerType);
+            }
+        }
+        Ok(())
+    }
+}
+
+// calls f on the matching tiles of the layer, with their position.
+fn visit_tiles<T: AnyTile + TileNumber>(
+    tiles: &Array2<T>,
+    query: &TilesQuery,
+    mut f: impl FnMut(usize, usize),
+) {
+    let (x, y, x_end, y_end) = query.region(tiles.dim());
+    for ((ty, tx), tile) in tiles.slice(s![y..y_end, x..x_end]).indexed_iter() {
+        if query.matches(tile) {
+            f(x + tx, y + ty);
+        }
+    }
+}
+
+fn visit_tiles_mut<T: AnyTile + TileNumber>(
+    tiles: &mut Array2<T>,
+    query: &TilesQuery,
+    mut f: impl FnMut(usize, usize, &mut T),
+) {
+    let (x, y, x_end, y_end) = query.region(tiles.dim());
+    for ((ty, tx), tile) in tiles.slice_mut(s![y..y_end, x..x_end]).indexed_iter_mut() {
+        if query.matches(tile) {
+            f(x + tx, y + ty, tile);
+        }
+    }
+}
+
+// `$unwrap` is `unwrap_ref` or `unwrap_mut`.
+macro_rules! for_tiles {
+    ($layer:expr, $unwrap:ident, $tiles:ident => $body:expr) => {
+        match $layer {
+            Layer::Game(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Tiles(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Front(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Tele(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Speedup(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Switch(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Tune(l) => {
+                let $tiles = l.tiles.$unwrap();
+                $body
+            }
+            Layer::Quads(_) | Layer::Sounds(_) | Layer::Invalid(_) => (),
+        }
+    };
+}
+
+pub fn find_tiles(map: &TwMap, query: &TilesQuery) -> Result<TilesFound, Error> {
+    query.check_layers(map)?;
+
+    let mut found = TilesFound {
+        layers: Vec::new(),
+        truncated: false,
+    };
+    let mut total = 0;
+
+    for (g, group) in map.groups.iter().enumerate() {
+        for (l, layer) in group.layers.iter().enumerate() {
+            let (g, l) = (g as u16, l as u16);
+            if !query.selects(g, l) {
+                continue;
+            }
+
+            let mut layer_tiles = LayerTiles {
+                group: g,
+                layer: l,
+                count: 0,
+                positions: Vec::new(),
+            };
+            for_tiles!(layer, unwrap_ref, tiles => visit_tiles(tiles, query, |x, y| {
+                layer_tiles.count += 1;
+                if total < MAX_FOUND_TILES {
+                    layer_tiles.positions.push((x as u32, y as u32));
+                    total += 1;
+                } else {
+                    found.truncated = true;
+                }
+            }));
+
+            if layer_tiles.count != 0 {
+                found.layers.push(layer_tiles);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+fn replace_tile<T: AnyTile + TileNumber>(tile: &mut T, replacement: &TilesReplacement) {
+    if replacement.id == 0 {
+        *tile = T::default();
+        return;
+    }
+
+    *tile.id_mut() = replacement.id;
+    if let (Some(flags), Some(tile_flags)) = (replacement.flags, tile.flags_mut()) {
+        *tile_flags = TileFlags::from_bits_truncate(flags);
+    }
+    if let (Some(number), Some(tile_number)) = (replacement.number, tile.number_mut()) {
+        *tile_number = number;
+    }
+}
+
+/// Returns the layers in which tiles were replaced.
+pub fn replace_tiles(
+    map: &mut TwMap,
+    query: &TilesQuery,
+    replacement: &TilesReplacement,
+) -> Result<Vec<LayerReplaced>, Error> {
+    query.check_layers(map)?;
+
+    let mut replaced = Vec::new();
+    for (g, group) in map.groups.iter_mut().enumerate() {
+        for (l, layer) in group.layers.iter_mut().enumerate() {
+            let (g, l) = (g as u16, l as u16);
+            if !query.selects(g, l) {
+                continue;
+            }
+
+            let mut count = 0;
+            let (mut min, mut max) = ((u32::MAX, u32::MAX), (0, 0));
+            for_tiles!(layer, unwrap_mut, tiles => visit_tiles_mut(tiles, query, |x, y, tile| {
+                replace_tile(tile, replacement);
+                count += 1;
+                let (x, y) = (x as u32, y as u32);
+                min = (min.0.min(x), min.1.min(y));
+                max = (max.0.max(x), max.1.max(y));
+            }));
+
+            if count != 0 {
+                replaced.push(LayerReplaced {
+                    group: g,
+                    layer: l,
+                    count,
+                    rect: Rect::new(min.0, min.1, max.0 + 1 - min.0, max.1 + 1 - min.1),
+                });
+            }
+        }
+    }
+
+    Ok(replaced)
+}
@@ -0,0 +1,158 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use twmap::{
+    automapper::{Automapper, Condition, IndexRule},
+    Tile, TileFlags,
+};
+
+// Remapping of the tile ids of a tiles layer, for when its image is replaced with a
+// tileset that has a different layout.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemapEntry {
+    pub from: u8,
+    /// Only remap the tiles with these flags, any flags if not given. The opaque flag is ignored.
+    #[serde(default)]
+    pub from_flags: Option<u8>,
+    pub to: u8,
+    /// New flags of the tiles, unchanged if not given.
+    #[serde(default)]
+    pub to_flags: Option<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Remapping {
+    Table {
+        entries: Vec<RemapEntry>,
+    },
+    /// Derived from the automappers of the old and new image: the rules at the same place
+    /// in configs of the same name, with the same neighbour offsets, produce matching tiles.
+    Automappers {
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemapTiles {
+    pub remapping: Remapping,
+    /// Also sets the image of the layer.
+    #[serde(default)]
+    pub image: Option<u16>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RemapReport {
+    pub remapped: usize,
+    /// Ids of the non-empty tiles of the layer that have no mapping and were left unchanged.
+    pub unmapped: Vec<u8>,
+}
+
+/// Lookup table from (id, flags) to the new tile, built from a remapping.
+pub struct TileMap {
+    map: HashMap<(u8, Option<u8>), (u8, Option<u8>)>,
+}
+
+fn strip_opaque(flags: TileFlags) -> u8 {
+    (flags - TileFlags::OPAQUE).bits()
+}
+
+// rules match when they check the same neighbours the same way, the tile ids they
+// compare to are not comparable across tilesets.
+fn same_shape(a: &IndexRule, b: &IndexRule) -> bool {
+    let kind = |c: &Condition| match c {
+        Condition::Empty => 0,
+        Condition::Full => 1,
+        Condition::WhiteList(_) => 2,
+        Condition::BlackList(_) => 3,
+    };
+    a.default_rule == b.default_rule
+        && a.conditions.len() == b.conditions.len()
+        && a.conditions
+            .iter()
+            .zip(&b.conditions)
+            .all(|(a, b)| a.offset == b.offset && kind(&a.condition) == kind(&b.condition))
+}
+
+impl TileMap {
+    pub fn from_entries(entries: &[RemapEntry]) -> Self {
+        let map = entries
+            .iter()
+            .map(|e| {
+                let from_flags = e.from_flags.map(|f| f & !TileFlags::OPAQUE.bits());
+                ((e.from, from_flags), (e.to, e.to_flags))
+            })
+            .collect();
+        TileMap { map }
+    }
+
+    pub fn from_automappers(from: &Automapper, to: &Automapper) -> Self {
+        let mut map = HashMap::new();
+        for config in &from.configs {
+            let Some(other) = to.configs.iter().find(|c| c.name == config.name) else {
+                continue;
+            };
+            for (run, other_run) in config.runs.iter().zip(&other.runs) {
+                for (rule, other_rule) in run.rules.iter().zip(&other_run.rules) {
+                    if !same_shape(rule, other_rule) {
+                        continue;
+                    }
+                    // the first matching rule wins, like in the automapper.
+                    let key = (rule.tile.id, Some(strip_opaque(rule.tile.flags)));
+                    let value = (
+                        other_rule.tile.id,
+                        Some(strip_opaque(other_rule.tile.flags)),
+                    );
+                    map.entry(key).or_insert(value);
+                    // tiles placed with other flags keep them.
+                    map.entry((rule.tile.id, None))
+                        .or_insert((other_rule.tile.id, None));
+                }
+            }
+        }
+        TileMap { map }
+    }
+
+    fn get(&self, tile: &Tile) -> Option<Tile> {
+        let flags = strip_opaque(tile.flags);
+        let &(id, new_flags) = self
+            .map
+            .get(&(tile.id, Some(flags)))
+            .or_else(|| self.map.get(&(tile.id, None)))?;
+
+        let flags = match new_flags {
+            Some(f) => TileFlags::from_bits_truncate(f) | (tile.flags & TileFlags::OPAQUE),
+            None => tile.flags,
+        };
+        Some(if id == 0 {
+            Tile::default()
+        } else {
+            Tile::new(id, flags)
+        })
+    }
+
+    pub fn apply<'a>(&self, tiles: impl IntoIterator<Item = &'a mut Tile>) -> RemapReport {
+        let mut remapped = 0;
+        let mut unmapped = BTreeSet::new();
+        for tile in tiles {
+            if tile.id == 0 {
+                continue;
+            }
+            match self.get(tile) {
+                Some(new) => {
+                    *tile = new;
+                    remapped += 1;
+                }
+                None => {
+                    unmapped.insert(tile.id);
+                }
+            }
+        }
+        RemapReport {
+            remapped,
+            unmapped: unmapped.into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+
+use ndarray::Array2;
+use serde::{de, Deserialize, Deserializer};
+use twmap::{automapper::ConfigOutOfBounds, AutomapperConfig, Tile, TileFlags};
+use vek::Vec2;
+
+use crate::{
+    error::Error,
+    protocol::{AutomapperDiagnostic, Severity, Span},
+};
+
+// Teeworlds 0.7 .json automappers. Only the "tileset" mappers are supported, the
+// "doodads" mappers place random decorations and have no equivalent in DDNet.
+//
+// The execution follows the 0.7 editor: every non-empty tile is first set to the base
+// tile, then all the rules are tested in order on the tiles that are not on the border
+// of the layer, and the last matching rule wins. Tiles are changed in place, so the
+// conditions see the tiles above and to the left already automapped.
+
+#[derive(Deserialize)]
+struct FileJson {
+    tileset: Option<Vec<HashMap<String, ConfigJson>>>,
+    doodads: Option<de::IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct ConfigJson {
+    #[serde(default)]
+    basetile: u8,
+    #[serde(default)]
+    rules: Vec<RuleJson>,
+}
+
+#[derive(Deserialize)]
+struct RuleJson {
+    #[serde(default)]
+    index: u8,
+    #[serde(default)]
+    random: Option<RandomJson>,
+    #[serde(default, deserialize_with = "deserialize_rotate")]
+    rotate: u16,
+    #[serde(default)]
+    hflip: u8,
+    #[serde(default)]
+    vflip: u8,
+    #[serde(default)]
+    condition: Vec<ConditionJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RandomJson {
+    /// One chance out of n.
+    OneOutOf(u32),
+    Probability(f32),
+}
+
+#[derive(Deserialize)]
+struct ConditionJson {
+    x: i32,
+    y: i32,
+    value: Value,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Named(NamedValue),
+    Index(u8),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamedValue {
+    Full,
+    Empty,
+}
+
+fn deserialize_rotate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let rotate = u16::deserialize(deserializer)?;
+    match rotate {
+        0 | 90 | 180 | 270 => Ok(rotate),
+        _ => Err(de::Error::custom(format!(
+            "invalid rotation {rotate}, expected 0, 90, 180 or 270"
+        ))),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TwCondition {
+    pub offset: Vec2<i32>,
+    pub value: Value,
+}
+
+#[derive(Clone, Debug)]
+pub struct TwRule {
+    pub tile: Tile,
+    /// Probability to apply the rule where the conditions match, always if not given.
+    pub probability: Option<f32>,
+    pub conditions: Vec<TwCondition>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TwConfig {
+    pub name: String,
+    pub base_tile: u8,
+    pub rules: Vec<TwRule>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TwAutomapper {
+    pub configs: Vec<TwConfig>,
+}
+
+// the flags set by the 0.7 editor, the "hflip" of the file is the FLIP_X of the tile
+// only when the tile is rotated.
+fn rule_flags(rule: &RuleJson) -> TileFlags {
+    let mut flags = match rule.rotate {
+        90 => TileFlags::ROTATE,
+        180 => TileFlags::FLIP_X | TileFlags::FLIP_Y,
+        270 => TileFlags::FLIP_X | TileFlags::FLIP_Y | TileFlags::ROTATE,
+        _ => TileFlags::empty(),
+    };
+    let rotated = flags.contains(TileFlags::ROTATE);
+    if rule.hflip != 0 {
+        flags ^= if rotated {
+            TileFlags::FLIP_Y
+        } else {
+            TileFlags::FLIP_X
+        };
+    }
+    if rule.vflip != 0 {
+        flags ^= if rotated {
+            TileFlags::FLIP_X
+        } else {
+            TileFlags::FLIP_Y
+        };
+    }
+    flags
+}
+
+impl From<RuleJson> for TwRule {
+    fn from(rule: RuleJson) -> Self {
+        let probability = match rule.random {
+            None => None,
+            Some(RandomJson::OneOutOf(n)) => Some(1.0 / n.max(1) as f32),
+            Some(RandomJson::Probability(p)) => Some(p.clamp(0.0, 1.0)),
+        }
+        .filter(|&p| p < 1.0);
+
+        TwRule {
+            tile: match rule.index {
+                0 => Tile::default(),
+                id => Tile::new(id, rule_flags(&rule)),
+            },
+            probability,
+            conditions: rule
+                .condition
+                .into_iter()
+                .map(|c| TwCondition {
+                    offset: Vec2::new(c.x, c.y),
+                    value: c.value,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn hash_u32(mut n: u32) -> u32 {
+    n ^= n >> 16;
+    n = n.wrapping_mul(0x7feb352d);
+    n ^= n >> 15;
+    n = n.wrapping_mul(0x846ca68b);
+    n ^= n >> 16;
+    n
+}
+
+// a random number in [0, 1) that only depends on the seed, the rule and the position,
+// so that automapping a part of a layer gives the same result as the whole layer.
+fn random_at(seed: u32, rule: usize, x: usize, y: usize) -> f32 {
+    let mut hash = seed;
+    for n in [rule as u32, x as u32, y as u32] {
+        hash = hash_u32(hash ^ n.wrapping_mul(0x9e3779b9));
+    }
+    (hash >> 8) as f32 / (1 << 24) as f32
+}
+
+impl TwCondition {
+    fn applies(&self, tiles: &Array2<Tile>, x: usize, y: usize) -> bool {
+        let (h, w) = tiles.dim();
+        // positions outside of the layer are clamped to the border.
+        let x = (x as i64 + self.offset.x as i64).clamp(0, w as i64 - 1) as usize;
+        let y = (y as i64 + self.offset.y as i64).clamp(0, h as i64 - 1) as usize;
+        let id = tiles[(y, x)].id;
+        match self.value {
+            Value::Named(NamedValue::Full) => id != 0,
+            Value::Named(NamedValue::Empty) => id == 0,
+            Value::Index(index) => id == index,
+        }
+    }
+}
+
+impl TwConfig {
+    pub fn run(&self, mut seed: u32, tiles: &mut Array2<Tile>) {
+        if seed == 0 {
+            seed = rand::random();
+        }
+        let (h, w) = tiles.dim();
+        for y in 0..h {
+            for x in 0..w {
+                let tile = &mut tiles[(y, x)];
+                if tile.id == 0 {
+                    continue;
+                }
+                *tile = match self.base_tile {
+                    0 => Tile::default(),
+                    id => Tile::new(id, tile.flags),
+                };
+
+                if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                    continue;
+                }
+
+                for (i, rule) in self.rules.iter().enumerate() {
+                    let applies = rule.conditions.iter().all(|c| c.applies(tiles, x, y))
+                        && rule
+                            .probability
+                            .is_none_or(|p| random_at(seed, i, x, y) < p);
+                    if applies {
+                        tiles[(y, x)] = rule.tile;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl TwAutomapper {
+    pub fn run(
+        &self,
+        automapper_config: &AutomapperConfig,
+        tiles: &mut Array2<Tile>,
+    ) -> Result<(), ConfigOutOfBounds> {
+        let Some(index) = automapper_config.config else {
+            return Ok(());
+        };
+        let config = self.configs.get(index as usize).ok_or(ConfigOutOfBounds)?;
+        config.run(automapper_config.seed, tiles);
+        Ok(())
+    }
+}
+
+fn diagnostic(line: usize, col: usize, msg: String) -> AutomapperDiagnostic {
+    AutomapperDiagnostic {
+        span: Span {
+            line_start: line as u32,
+            col_start: col as u32,
+            line_end: line as u32,
+            col_end: col as u32 + 1,
+        },
+        msg,
+        severity: Severity::Error,
+    }
+}
+
+/// Parses a Teeworlds .json automapper. The automapper is only usable if there are no errors.
+pub fn parse_tw_automapper(file: &str) -> (TwAutomapper, Vec<AutomapperDiagnostic>) {
+    let json: FileJson = match serde_json::from_str(file) {
+        Ok(json) => json,
+        Err(e) => {
+            let msg = e.to_string();
+            // serde_json appends the location to the message.
+            let msg = msg
+                .rsplit_once(" at line ")
+                .map_or(msg.as_str(), |(m, _)| m)
+                .to_owned();
+            let diagnostic = diagnostic(e.line().max(1), e.column().max(1), msg);
+            return (TwAutomapper::default(), vec![diagnostic]);
+        }
+    };
+
+    let Some(tileset) = json.tileset else {
+        let msg = if json.doodads.is_some() {
+            "doodads automappers are not supported, only tileset automappers"
+        } else {
+            "missing \"tileset\""
+        };
+        return (
+            TwAutomapper::default(),
+            vec![diagnostic(1, 1, msg.to_owned())],
+        );
+    };
+
+    let configs = tileset
+        .into_iter()
+        // like in teeworlds, each config is an object with its name as only key.
+        .filter(|c| c.len() == 1)
+        .flat_map(|c| c.into_iter())
+        .map(|(name, config)| TwConfig {
+            name,
+            base_tile: config.basetile,
+            rules: config.rules.into_iter().map(TwRule::from).collect(),
+        })
+        .collect();
+
+    (TwAutomapper { configs }, vec![])
+}
+
+/// Parses a Teeworlds .json automapper, failing on the first error.
+pub fn load_tw_automapper(file: &str) -> Result<TwAutomapper, Error> {
+    let (automapper, diagnostics) = parse_tw_automapper(file);
+    match diagnostics.first() {
+        Some(d) => Err(Error::Automapper(format!(
+            "line {}: {}",
+            d.span.line_start, d.msg
+        ))),
+        None => Ok(automapper),
+    }
+}
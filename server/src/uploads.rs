@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+// Files too large for a single request (maps and images) can be uploaded in chunks,
+// over http or websocket. An upload is not tied to the connection that started it,
+// so an interrupted upload is resumed by sending the chunks from the `received`
+// offset of its status. Once complete, the upload is used by its id to create a map
+// or an image.
+
+const MAX_UPLOADS: usize = 16;
+// uploads without activity for this long are discarded.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadStatus {
+    pub id: String,
+    pub size: usize,
+    pub received: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkParams {
+    pub offset: usize,
+}
+
+struct Upload {
+    size: usize,
+    data: Vec<u8>,
+    last_active: Instant,
+}
+
+pub struct Uploads {
+    max_size: usize,
+    uploads: Mutex<HashMap<String, Upload>>,
+}
+
+impl Uploads {
+    pub fn new(max_size: usize) -> Self {
+        Uploads {
+            max_size,
+            uploads: Default::default(),
+        }
+    }
+
+    fn uploads(&self) -> MutexGuard<'_, HashMap<String, Upload>> {
+        let mut uploads = self.uploads.lock().expect("failed to lock uploads");
+        uploads.retain(|_, upload| upload.last_active.elapsed() < UPLOAD_TIMEOUT);
+        uploads
+    }
+
+    fn status(id: &str, upload: &Upload) -> UploadStatus {
+        UploadStatus {
+            id: id.to_owned(),
+            size: upload.size,
+            received: upload.data.len(),
+        }
+    }
+
+    pub fn start(&self, size: usize) -> Result<UploadStatus, Error> {
+        if size > self.max_size {
+            return Err(Error::UploadTooBig);
+        }
+
+        let mut uploads = self.uploads();
+        if uploads.len() >= MAX_UPLOADS {
+            return Err(Error::MaxUploads);
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let upload = Upload {
+            size,
+            data: Vec::new(),
+            last_active: Instant::now(),
+        };
+        let status = Self::status(&id, &upload);
+        uploads.insert(id, upload);
+        Ok(status)
+    }
+
+    pub fn get(&self, id: &str) -> Result<UploadStatus, Error> {
+        let uploads = self.uploads();
+        let upload = uploads.get(id).ok_or(Error::UploadNotFound)?;
+        Ok(Self::status(id, upload))
+    }
+
+    /// Chunks can overlap the received data (e.g. when resending a chunk which was
+    /// not acknowledged) but not leave a gap. The overlapping part must be identical
+    /// to the received data.
+    pub fn chunk(&self, id: &str, offset: usize, chunk: &[u8]) -> Result<UploadStatus, Error> {
+        let mut uploads = self.uploads();
+        let upload = uploads.get_mut(id).ok_or(Error::UploadNotFound)?;
+
+        if offset > upload.data.len() {
+            return Err(Error::Invalid("chunk offset"));
+        }
+        if offset + chunk.len() > upload.size {
+            return Err(Error::UploadTooBig);
+        }
+
+        let overlap = (upload.data.len() - offset).min(chunk.len());
+        if upload.data[offset..offset + overlap] != chunk[..overlap] {
+            return Err(Error::Invalid("chunk, it differs from the received data"));
+        }
+
+        upload.data.extend_from_slice(&chunk[overlap..]);
+        upload.last_active = Instant::now();
+        Ok(Self::status(id, upload))
+    }
+
+    pub fn cancel(&self, id: &str) -> Result<(), Error> {
+        self.uploads()
+            .remove(id)
+            .map(|_| ())
+            .ok_or(Error::UploadNotFound)
+    }
+
+    /// The content of a complete upload. The upload is kept until it is removed, so
+    /// that a request using it can be retried if it fails.
+    pub fn data(&self, id: &str) -> Result<Vec<u8>, Error> {
+        let uploads = self.uploads();
+        let upload = uploads.get(id).ok_or(Error::UploadNotFound)?;
+
+        if upload.data.len() != upload.size {
+            return Err(Error::UploadIncomplete);
+        }
+
+        Ok(upload.data.clone())
+    }
+
+    /// Removes an upload once it was used by a successful request.
+    pub fn remove(&self, id: &str) {
+        self.uploads().remove(id);
+    }
+}
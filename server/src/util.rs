@@ -5,6 +5,9 @@ use crate::{
     twmap_map_edit::{extend_layer, shrink_layer},
 };
 
+// the game does not load more images per map.
+pub(crate) const MAX_IMAGES: usize = 64;
+
 pub(crate) fn timestamp_now() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::SystemTime::UNIX_EPOCH)